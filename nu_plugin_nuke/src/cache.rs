@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use nu_protocol::{Config, Record, Span, Value};
+
+use crate::Task;
+
+/// A content-addressed store of task targets, which any checkout may share.
+/// The values returned by the tasks are kept next to their targets.
+/// Once it outgrows its size, the least recently used targets are evicted.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// The number of cached targets and their total size in bytes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub entries: usize,
+    pub size: u64,
+}
+
+impl Cache {
+    pub const DEFAULT_MAX_SIZE: u64 = 5 << 30;
+
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Derives the key of the task from its fingerprint, the values of its `--vars` and `--stamp`,
+    /// the contents of its files, including those `discovered` in its depfile,
+    /// and the values returned by its dependencies.
+    /// Paths are taken relative to the working directory, so they match across checkouts.
+    pub fn key(
+        task: &Task,
+        discovered: &[PathBuf],
+        stamps: &BTreeMap<String, String>,
+        input: &Record,
+    ) -> io::Result<String> {
+        let relative = |path: &Path| {
+            path.strip_prefix(task.workdir())
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let mut hasher = blake3::Hasher::new();
+
+        hasher.update(task.fingerprint().as_bytes());
+        for file in task.files().iter().chain(discovered) {
+            let mut file_hasher = blake3::Hasher::new();
+            file_hasher.update_reader(File::open(file)?)?;
+            hasher.update(format!("{:?}", (relative(file), file_hasher.finalize())).as_bytes());
+        }
+        let config = Config::default();
+        let input: Vec<_> = input
+            .iter()
+            .map(|(name, val)| (name, val.to_expanded_string(" ", &config)))
+            .collect();
+        hasher.update(format!("{:?}", (stamps, input)).as_bytes());
+
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /// Copies the cached target into place, returning the value the task returned,
+    /// or `None` if there was no target.
+    pub fn restore(&self, key: &str, target: &Path, span: Span) -> io::Result<Option<Value>> {
+        let object = self.object(key);
+        if !object.is_file() {
+            return Ok(None);
+        }
+        let output = match fs::read(self.output(key)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Value::nothing(span),
+            Err(e) => return Err(e),
+        };
+
+        // Marks the object as recently used.
+        File::options()
+            .append(true)
+            .open(&object)?
+            .set_modified(SystemTime::now())?;
+        fs::copy(object, target)?;
+        Ok(Some(output))
+    }
+
+    /// Stores the target along with the value the task returned.
+    pub fn store(&self, key: &str, target: &Path, output: &Value) -> io::Result<()> {
+        let object = self.object(key);
+        let tmp_path = |path: &Path| path.with_extension(format!("{}.tmp", std::process::id()));
+
+        // The output goes first, so the object is never found without it.
+        let output_path = self.output(key);
+        if output.is_nothing() {
+            remove_if_exists(&output_path)?;
+        } else {
+            let output = serde_json::to_vec(output)?;
+            fs::create_dir_all(self.dir.join("outputs"))?;
+            let tmp = tmp_path(&output_path);
+            fs::write(&tmp, output)?;
+            fs::rename(tmp, output_path)?;
+        }
+
+        fs::create_dir_all(self.dir.join("objects"))?;
+        // Written aside first, so a concurrent reader never sees a truncated object.
+        let tmp = tmp_path(&object);
+        fs::copy(target, &tmp)?;
+        fs::rename(tmp, object)?;
+
+        self.prune()?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> io::Result<Stats> {
+        let mut stats = Stats::default();
+        for (_, _, size) in self.objects()? {
+            stats.entries += 1;
+            stats.size += size;
+        }
+        Ok(stats)
+    }
+
+    /// Evicts the least recently used targets until the cache fits in its size,
+    /// returning what was evicted.
+    pub fn prune(&self) -> io::Result<Stats> {
+        let mut objects = self.objects()?;
+        let mut size: u64 = objects.iter().map(|&(_, _, size)| size).sum();
+        objects.sort_by_key(|&(_, used, _)| used);
+
+        let mut evicted = Stats::default();
+        for (path, _, object_size) in objects {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            if let Some(key) = path.file_name().and_then(|key| key.to_str()) {
+                remove_if_exists(&self.output(key))?;
+            }
+            size -= object_size;
+            evicted.entries += 1;
+            evicted.size += object_size;
+        }
+        Ok(evicted)
+    }
+
+    fn object(&self, key: &str) -> PathBuf {
+        self.dir.join("objects").join(key)
+    }
+
+    fn output(&self, key: &str) -> PathBuf {
+        self.dir.join("outputs").join(key)
+    }
+
+    /// Each object with the time it was last used and its size.
+    fn objects(&self) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let entries = match fs::read_dir(self.dir.join("objects")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut objects = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && entry.path().extension().is_none() {
+                objects.push((entry.path(), metadata.modified()?, metadata.len()));
+            }
+        }
+        Ok(objects)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use nu_protocol::{Span, Value};
+
+    use super::Cache;
+
+    #[test]
+    fn outputs_follow_their_objects() {
+        let dir = std::env::temp_dir().join(format!("nuke-cache-{}", std::process::id()));
+        let target = dir.join("target");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&target, "built").unwrap();
+        let span = Span::test_data();
+        let cache = Cache::new(dir.join("cache"), u64::MAX);
+
+        cache
+            .store("with", &target, &Value::string("1.2.0", span))
+            .unwrap();
+        cache
+            .store("without", &target, &Value::nothing(span))
+            .unwrap();
+        fs::remove_file(&target).unwrap();
+
+        let output = cache.restore("with", &target, span).unwrap();
+        assert_eq!(output, Some(Value::string("1.2.0", span)));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "built");
+        let output = cache.restore("without", &target, span).unwrap();
+        assert_eq!(output, Some(Value::nothing(span)));
+        assert_eq!(cache.restore("missing", &target, span).unwrap(), None);
+
+        let cache = Cache::new(dir.join("cache"), 0);
+        assert_eq!(cache.prune().unwrap().entries, 2);
+        assert!(!cache.output("with").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+/// The prerequisites of all rules in a Makefile-format depfile, such as written by `gcc -MD`,
+/// in order and without duplicates.
+pub fn parse(content: &str) -> Vec<String> {
+    let content = content.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut deps = vec![];
+
+    for line in content.lines() {
+        let Some(colon) = rule_colon(line) else {
+            continue;
+        };
+        for dep in split(&line[colon + 1..]) {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+    }
+
+    deps
+}
+
+/// The colon separating targets from prerequisites, which is followed by whitespace,
+/// unlike those of Windows drive letters.
+fn rule_colon(line: &str) -> Option<usize> {
+    line.char_indices()
+        .find(|&(i, c)| c == ':' && line[i + 1..].chars().next().is_none_or(char::is_whitespace))
+        .map(|(i, _)| i)
+}
+
+/// Splits the prerequisites at whitespace, unescaping `\ `, `\#` and `$$`.
+fn split(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ' | '#')) => word.push(chars.next().unwrap()),
+            '$' if chars.peek() == Some(&'$') => word.push(chars.next().unwrap()),
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn gcc_md_mp() {
+        let content = "\
+build/main.o: src/main.c src/util.h \\
+ /usr/include/stdio.h
+src/util.h:
+/usr/include/stdio.h:
+";
+        assert_eq!(
+            parse(content),
+            ["src/main.c", "src/util.h", "/usr/include/stdio.h"]
+        );
+    }
+
+    #[test]
+    fn crlf_continuations() {
+        let content = "main.o: main.c \\\r\n util.h\r\n";
+        assert_eq!(parse(content), ["main.c", "util.h"]);
+    }
+
+    #[test]
+    fn escaped_spaces() {
+        let content = "build/my\\ file.o: src/my\\ file.c include/a\\ b.h\n";
+        assert_eq!(parse(content), ["src/my file.c", "include/a b.h"]);
+    }
+
+    #[test]
+    fn escaped_hashes_and_dollars() {
+        let content = "out.o: x\\#y.h cost$$.c\n";
+        assert_eq!(parse(content), ["x#y.h", "cost$.c"]);
+    }
+
+    #[test]
+    fn windows_drive_letters() {
+        let content = "C:\\build\\main.o: C:\\src\\main.c D:\\include\\util.h\n";
+        assert_eq!(parse(content), ["C:\\src\\main.c", "D:\\include\\util.h"]);
+    }
+
+    #[test]
+    fn several_targets() {
+        let content = "main.o main.d: main.c\n";
+        assert_eq!(parse(content), ["main.c"]);
+    }
+}
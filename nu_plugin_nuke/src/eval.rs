@@ -0,0 +1,220 @@
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use nu_plugin::{EngineInterface, EvaluatedCall};
+use nu_protocol::engine::Closure;
+use nu_protocol::{
+    ByteStream, DeclId, PipelineData, Record, ShellError, Signals, Span, Spanned, Value,
+};
+
+use crate::utils::time::humanize;
+
+const TIMED_OUT: &str = "Task timed out";
+
+/// Whether the evaluation failed by outliving its timeout.
+pub fn timed_out(e: &ShellError) -> bool {
+    matches!(e, ShellError::GenericError { error, .. } if error == TIMED_OUT)
+}
+
+/// Evaluates task closures inside their working directories and environments.
+#[derive(Debug, Clone)]
+pub struct Evaluator {
+    engine: EngineInterface,
+    cd: DeclId,
+    load_env: DeclId,
+    hide_env: DeclId,
+    /// `cd` and `load-env` change the stack of the whole plugin call, which closures inherit,
+    /// so they must stay paired with the evaluation following them.
+    /// Nu answers the engine calls of a plugin call one at a time, so the statements of
+    /// closures never run together anyway; only the streams they return are read in parallel,
+    /// with the lock released.
+    cwd_lock: Arc<Mutex<()>>,
+}
+
+impl Evaluator {
+    pub fn new(engine: &EngineInterface, span: Span) -> Result<Self, ShellError> {
+        let find_decl = |name: &str| {
+            engine
+                .find_decl(name)?
+                .ok_or(ShellError::CommandNotFound { span })
+        };
+
+        Ok(Self {
+            engine: engine.clone(),
+            cd: find_decl("cd")?,
+            load_env: find_decl("load-env")?,
+            hide_env: find_decl("hide-env")?,
+            cwd_lock: Arc::default(),
+        })
+    }
+
+    /// - `args` are passed to the parameters of the closure in order.
+    /// - Once `timeout` elapses, the evaluation fails, but the closure itself isn't stopped,
+    ///   as nu offers plugins no way to interrupt it. Only the reading of its output stops,
+    ///   while a closure hung before its last pipeline keeps holding up the engine, and with
+    ///   it the rest of the run, until it returns.
+    /// - With `console`, the standard output of external commands goes to the terminal.
+    pub fn eval(
+        &self,
+        run: &Spanned<Closure>,
+        workdir: &Path,
+        env: Record,
+        args: &[Value],
+        timeout: Option<Duration>,
+        console: bool,
+    ) -> Result<Value, ShellError> {
+        let Some(timeout) = timeout else {
+            return self.eval_impl(run, workdir, env, args.to_vec(), console, Signals::EMPTY);
+        };
+
+        let signals = Signals::new(Arc::new(AtomicBool::new(false)));
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::spawn({
+            let this = self.clone();
+            let run = run.clone();
+            let workdir = workdir.to_path_buf();
+            let args = args.to_vec();
+            let signals = signals.clone();
+            move || {
+                let _ = tx.send(this.eval_impl(&run, &workdir, env, args, console, signals));
+            }
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => {
+                // Lets the abandoned thread stop at the next chunk of output.
+                signals.trigger();
+                Err(ShellError::GenericError {
+                    error: TIMED_OUT.into(),
+                    msg: format!("timed out after {}", humanize(timeout)),
+                    span: Some(run.span),
+                    help: None,
+                    inner: vec![],
+                })
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(ShellError::NushellFailedSpanned {
+                msg: "Task evaluation panicked".into(),
+                label: "while running this closure".into(),
+                span: run.span,
+            }),
+        }
+    }
+
+    pub fn env_var(&self, name: &str) -> Result<Option<Value>, ShellError> {
+        self.engine.get_env_var(name)
+    }
+
+    pub fn cd(&self, dir: &Path, span: Span) -> Result<(), ShellError> {
+        let call =
+            EvaluatedCall::new(span).with_positional(Value::string(dir.to_string_lossy(), span));
+        self.engine
+            .call_decl(self.cd, call, PipelineData::Empty, false, false)?;
+        Ok(())
+    }
+
+    /// Sets the environment variables, returning their previous values.
+    fn load_env(
+        &self,
+        env: Record,
+        span: Span,
+    ) -> Result<Vec<(String, Option<Value>)>, ShellError> {
+        if env.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let saved = env
+            .columns()
+            .map(|name| Ok((name.clone(), self.engine.get_env_var(name)?)))
+            .collect::<Result<_, ShellError>>()?;
+        let call = EvaluatedCall::new(span).with_positional(Value::record(env, span));
+        self.engine
+            .call_decl(self.load_env, call, PipelineData::Empty, false, false)?;
+        Ok(saved)
+    }
+
+    fn restore_env(
+        &self,
+        saved: Vec<(String, Option<Value>)>,
+        span: Span,
+    ) -> Result<(), ShellError> {
+        let mut set = Record::new();
+        let mut call = EvaluatedCall::new(span);
+        for (name, val) in saved {
+            match val {
+                Some(val) => set.push(name, val),
+                None => {
+                    call.add_positional(Value::string(name, span));
+                }
+            }
+        }
+
+        if !set.is_empty() {
+            let call = EvaluatedCall::new(span).with_positional(Value::record(set, span));
+            self.engine
+                .call_decl(self.load_env, call, PipelineData::Empty, false, false)?;
+        }
+        if !call.positional.is_empty() {
+            self.engine
+                .call_decl(self.hide_env, call, PipelineData::Empty, false, false)?;
+        }
+        Ok(())
+    }
+
+    fn eval_impl(
+        &self,
+        run: &Spanned<Closure>,
+        workdir: &Path,
+        env: Record,
+        args: Vec<Value>,
+        console: bool,
+        signals: Signals,
+    ) -> Result<Value, ShellError> {
+        let output = {
+            let _guard = self.cwd_lock.lock().unwrap();
+            self.cd(workdir, run.span)?;
+
+            // The closure captures the environment as it's called, so it can be restored right after.
+            let saved = self.load_env(env, run.span)?;
+            let output = self.engine.eval_closure_with_stream(
+                run,
+                args,
+                PipelineData::Empty,
+                !console,
+                false,
+            );
+            self.restore_env(saved, run.span)?;
+            output?
+        };
+
+        let value = match output {
+            PipelineData::ByteStream(stream, _) => {
+                let span = stream.span();
+                let type_ = stream.type_();
+                match stream.reader() {
+                    Some(reader) => ByteStream::read(reader, span, signals, type_).into_value()?,
+                    None => Value::nothing(span),
+                }
+            }
+            PipelineData::ListStream(stream, _) => {
+                let span = stream.span();
+                let mut vals = vec![];
+                for val in stream {
+                    signals.check(span)?;
+                    vals.push(val);
+                }
+                Value::list(vals, span)
+            }
+            output => output.into_value(run.span)?,
+        };
+
+        match value {
+            Value::Error { error, .. } => Err(*error),
+            value => Ok(value),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use nu_protocol::{ShellError, Span, Spanned};
+
+/// Expands the `--files` of a task into concrete paths.
+#[derive(Debug)]
+pub struct FileCollector {
+    workdir: PathBuf,
+    excludes: Arc<Gitignore>,
+    ignore_files: bool,
+}
+
+impl FileCollector {
+    /// - `excludes` follow the syntax of `.gitignore`, relative to `workdir`.
+    /// - `ignore_files` makes the collector honor `.gitignore` and `.ignore` files.
+    pub fn new(
+        workdir: &Path,
+        excludes: &[Spanned<String>],
+        ignore_files: bool,
+    ) -> Result<Self, ShellError> {
+        let mut builder = GitignoreBuilder::new(workdir);
+        for pattern in excludes {
+            builder
+                .add_line(None, &pattern.item)
+                .map_err(|e| ShellError::InvalidGlobPattern {
+                    msg: e.to_string(),
+                    span: pattern.span,
+                })?;
+        }
+        let excludes = builder.build().map_err(|e| ShellError::GenericError {
+            error: "Invalid exclude patterns".into(),
+            msg: e.to_string(),
+            span: None,
+            help: None,
+            inner: vec![],
+        })?;
+
+        Ok(Self {
+            workdir: workdir.to_path_buf(),
+            excludes: Arc::new(excludes),
+            ignore_files,
+        })
+    }
+
+    /// A file is taken as-is, while a directory is expanded into the files inside it.
+    pub fn path(&self, path: &str) -> Vec<PathBuf> {
+        let path = self.workdir.join(path);
+
+        if path.is_dir() {
+            self.walk(&path, None).collect()
+        } else {
+            vec![path]
+        }
+    }
+
+    pub fn glob(&self, pattern: &str, span: Span) -> Result<Vec<PathBuf>, ShellError> {
+        let pattern = self.workdir.join(pattern);
+        let matcher = GlobBuilder::new(&pattern.to_string_lossy())
+            .literal_separator(true)
+            .build()
+            .map_err(|e| ShellError::InvalidGlobPattern {
+                msg: e.kind().to_string(),
+                span,
+            })?
+            .compile_matcher();
+
+        // Walk from the deepest directory free of metacharacters,
+        // no further than the pattern could possibly match.
+        let mut base = PathBuf::new();
+        for comp in pattern.components() {
+            if let Component::Normal(s) = comp
+                && s.to_string_lossy().contains(['*', '?', '[', '{'])
+            {
+                break;
+            }
+            base.push(comp);
+        }
+        let max_depth = (!pattern.to_string_lossy().contains("**"))
+            .then(|| pattern.components().count() - base.components().count());
+
+        Ok(self
+            .walk(&base, max_depth)
+            .filter(|p| matcher.is_match(p))
+            .collect())
+    }
+
+    fn walk(&self, root: &Path, max_depth: Option<usize>) -> impl Iterator<Item = PathBuf> {
+        let excludes = self.excludes.clone();
+
+        WalkBuilder::new(root)
+            .standard_filters(self.ignore_files)
+            .hidden(false)
+            .require_git(false)
+            .max_depth(max_depth)
+            .sort_by_file_name(Ord::cmp)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+                !excludes.matched(entry.path(), is_dir).is_ignore()
+            })
+            .build()
+            .flatten()
+            .map(ignore::DirEntry::into_path)
+            .filter(|p| p.is_file())
+    }
+}
@@ -1,27 +1,79 @@
-use ahash::HashMap;
-use nu_protocol::{LabeledError, Spanned};
+use std::path::{Path, PathBuf};
 
-use crate::{Scheduler, Task, TaskId};
+use ahash::{HashMap, HashSet};
+use nu_protocol::{LabeledError, ShellError, Spanned};
+
+use crate::eval::Evaluator;
+use crate::task::qualify;
+use crate::{DepKind, Rule, Scheduler, Task, TaskId};
 
 #[derive(Debug)]
 pub struct TaskGraph<'t, 's> {
     name2task: HashMap<&'t str, &'t Task>,
     name2id: HashMap<&'t str, Option<TaskId>>,
+    rules: Vec<&'t Rule>,
+    optional: OptionalDeps,
     sched: &'s mut Scheduler<'t>,
+    evaluator: &'s Evaluator,
+}
+
+/// Which optional dependencies to submit, provided they're defined.
+#[derive(Debug)]
+pub struct OptionalDeps {
+    /// Whether to submit those not listed below.
+    pub all: bool,
+    pub with: HashSet<String>,
+    pub without: HashSet<String>,
+}
+
+impl Default for OptionalDeps {
+    fn default() -> Self {
+        Self {
+            all: true,
+            with: HashSet::default(),
+            without: HashSet::default(),
+        }
+    }
+}
+
+impl OptionalDeps {
+    fn wants(&self, name: &str) -> bool {
+        !self.without.contains(name) && (self.all || self.with.contains(name))
+    }
 }
 
 impl<'t, 's> TaskGraph<'t, 's>
 where
     't: 's,
 {
-    pub fn new(tasks: impl Iterator<Item = &'t Task>, sched: &'s mut Scheduler<'t>) -> Self {
+    /// Of the tasks sharing a name, the last one is kept, as `nuke task --override` expects.
+    /// The evaluator runs the closures given to `--deps`.
+    pub fn new(
+        tasks: impl Iterator<Item = &'t Task>,
+        sched: &'s mut Scheduler<'t>,
+        evaluator: &'s Evaluator,
+    ) -> Self {
         Self {
             name2task: tasks.map(|task| (task.name(), task)).collect(),
             name2id: Default::default(),
+            rules: vec![],
+            optional: OptionalDeps::default(),
             sched,
+            evaluator,
         }
     }
 
+    /// Rules instantiating the dependencies and files that no task provides.
+    pub fn with_rules(mut self, rules: impl Iterator<Item = &'t Rule>) -> Self {
+        self.rules = rules.collect();
+        self
+    }
+
+    pub fn with_optional_deps(mut self, optional: OptionalDeps) -> Self {
+        self.optional = optional;
+        self
+    }
+
     pub fn submit(&mut self, task: &'t Task) -> Result<(), LabeledError> {
         self.submit_impl(task.name(), task)?;
         Ok(())
@@ -38,16 +90,47 @@ impl<'t> TaskGraph<'t, '_> {
 
         self.name2id.insert(name, None);
 
-        let mut deps = vec![];
-        for dname in task.deps() {
+        let (computed_optional_deps, computed_deps): (Vec<_>, Vec<_>) = self
+            .computed_deps(task)?
+            .into_iter()
+            .partition(|&(_, optional)| optional);
+        let computed_deps: Vec<_> = computed_deps.into_iter().map(|(name, _)| name).collect();
+        let computed_optional_deps: Vec<_> = computed_optional_deps
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let deps = task
+            .deps()
+            .iter()
+            .chain(&computed_deps)
+            .map(|dname| (dname, DepKind::Normal));
+        let after = task.after().iter().map(|dname| (dname, DepKind::OrderOnly));
+        let optional_deps = task
+            .optional_deps()
+            .iter()
+            .chain(&computed_optional_deps)
+            .filter(|dname| self.optional.wants(&dname.item))
+            .filter(|dname| self.name2task.contains_key(dname.item.as_str()))
+            .map(|dname| (dname, DepKind::Normal));
+
+        let mut dep_tasks = vec![];
+        for (dname, kind) in deps.chain(after).chain(optional_deps).collect::<Vec<_>>() {
             let dep_task = self
-                .name2task
-                .get(dname.item.as_str())
-                .copied()
+                .task(&dname.item)
                 .ok_or_else(|| SubmitError::TaskNotFound {
                     name: dname.clone(),
                 })?;
-            let dep_id = self.submit_impl(&dname.item, dep_task).map_err(|e| {
+            dep_tasks.push((dep_task, kind));
+        }
+        for file in &task.files {
+            if let Some(dep_task) = self.instantiate(|_| Some(file.clone())) {
+                dep_tasks.push((dep_task, DepKind::Normal));
+            }
+        }
+
+        let mut dep_ids = vec![];
+        for (dep_task, kind) in dep_tasks {
+            let dep_id = self.submit_impl(dep_task.name(), dep_task).map_err(|e| {
                 if let SubmitError::FoundCircularDep = e {
                     SubmitError::CircularDep {
                         parsing: dep_task.name_span().map(Into::into),
@@ -57,13 +140,80 @@ impl<'t> TaskGraph<'t, '_> {
                     e
                 }
             })?;
-            deps.push(dep_id);
+            dep_ids.push((dep_id, kind));
         }
 
-        let id = self.sched.add_task(task, &deps);
+        let id = self.sched.add_task(task, &dep_ids);
         self.name2id.insert(name, Some(id));
         Ok(id)
     }
+
+    /// Evaluates the closure given to `--deps`, returning the tasks with whether they're optional.
+    /// The names share the span of the closure.
+    fn computed_deps(&self, task: &Task) -> Result<Vec<(Spanned<String>, bool)>, SubmitError> {
+        let Some(deps_fn) = task.deps_fn() else {
+            return Ok(vec![]);
+        };
+
+        let env = self.sched.task_env(task);
+        let names = self
+            .evaluator
+            .eval(deps_fn, task.workdir(), env, &[], None, false)
+            .and_then(|val| val.into_list())
+            .and_then(|vals| {
+                vals.into_iter()
+                    .map(|val| val.coerce_into_string())
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(SubmitError::Eval)?;
+
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let (item, optional) = match name.strip_prefix('?') {
+                    Some(item) => (item.to_owned(), true),
+                    None => (name, false),
+                };
+                let span = deps_fn.span;
+                (qualify(task.namespace(), Spanned { item, span }), optional)
+            })
+            .collect())
+    }
+
+    /// The task of the name, instantiated from a rule if none is defined.
+    fn task(&mut self, name: &str) -> Option<&'t Task> {
+        match self.name2task.get(name) {
+            Some(&task) => Some(task),
+            None => self.instantiate(|rule| rule.path(name)),
+        }
+    }
+
+    /// Instantiates the first rule able to build the path, which may vary with the rule.
+    fn instantiate(&mut self, path: impl Fn(&Rule) -> Option<PathBuf>) -> Option<&'t Task> {
+        let (rule, stem) = self.rules.iter().find_map(|&rule| {
+            let path = path(rule)?;
+            let stem = rule.stem(&path)?;
+            self.can_make(&rule.source(stem), self.rules.len())
+                .then(|| (rule, stem.to_owned()))
+        })?;
+
+        if let Some(&task) = self.name2task.get(rule.name(&stem).as_str()) {
+            return Some(task);
+        }
+        let task = rule.instantiate(&stem);
+        self.name2task.insert(task.name(), task);
+        Some(task)
+    }
+
+    /// Whether the file exists or can be built by chaining at most `depth` rules.
+    fn can_make(&self, path: &Path, depth: usize) -> bool {
+        path.exists()
+            || depth > 0
+                && self.rules.iter().any(|rule| {
+                    rule.stem(path)
+                        .is_some_and(|stem| self.can_make(&rule.source(stem), depth - 1))
+                })
+    }
 }
 
 #[derive(Debug)]
@@ -76,20 +226,21 @@ enum SubmitError {
         dep: Spanned<String>,
     },
     FoundCircularDep,
+    Eval(ShellError),
 }
 
 impl From<SubmitError> for LabeledError {
     fn from(e: SubmitError) -> Self {
         match e {
-            SubmitError::TaskNotFound { name } => {
-                LabeledError::new("Task not found").with_label("Task not found", name.span)
-            }
+            SubmitError::TaskNotFound { name } => LabeledError::new("Task not found")
+                .with_label(format!("Task `{}` not found", name.item), name.span),
             SubmitError::CircularDep { parsing, dep } => {
                 LabeledError::new("Circular dependency between tasks")
                     .with_label("When parsing task here...", parsing.span)
                     .with_label("...it provides the task", dep.span)
             }
             SubmitError::FoundCircularDep => unreachable!(),
+            SubmitError::Eval(e) => e.into(),
         }
     }
 }
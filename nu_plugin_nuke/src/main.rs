@@ -1,15 +1,24 @@
+#![allow(clippy::result_large_err)]
+
+mod cache;
+mod depfile;
+mod eval;
+mod files;
 mod graph;
 mod plugin;
+mod rule;
 mod sched;
+mod state;
 mod task;
 mod utils;
 
 use nu_plugin::{MsgPackSerializer, serve_plugin};
 
-pub use graph::TaskGraph;
+pub use graph::{OptionalDeps, TaskGraph};
 pub use plugin::NukePlugin;
-pub use sched::{Scheduler, TaskId};
-pub use task::Task;
+pub use rule::Rule;
+pub use sched::{DepKind, Report, Scheduler, Status, TaskId};
+pub use task::{Task, Weight};
 
 fn main() {
     serve_plugin(&NukePlugin::default(), MsgPackSerializer)
@@ -1,20 +1,89 @@
-use std::num::NonZero;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::num::{NonZero, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 
-use glob::glob;
+use ahash::{HashMap, HashSet};
 use nu_plugin::{EngineInterface, EvaluatedCall, Plugin, PluginCommand, SimplePluginCommand};
 use nu_protocol::engine::Closure;
+use nu_protocol::shell_error::io::IoError;
 use nu_protocol::{
-    FromValue, LabeledError, ShellError, Signature, Spanned, SyntaxShape, Type, Value,
+    Config, FromValue, LabeledError, Record, ShellError, Signature, Spanned, SyntaxShape, Type,
+    Value, record,
 };
 
-use crate::{Scheduler, Task, TaskGraph};
+use crate::cache::Cache;
+use crate::eval::Evaluator;
+use crate::files::FileCollector;
+use crate::state::State;
+use crate::task::qualify;
+use crate::utils::call::EvaluatedCallExt;
+use crate::{OptionalDeps, Rule, Scheduler, Task, TaskGraph, Weight};
 
 #[derive(Debug, Default)]
 pub struct NukePlugin {
     tasks: Arc<boxcar::Vec<Task>>,
+    rules: Arc<boxcar::Vec<Rule>>,
+    /// The directory of the top-level make.nu, where the state of nuke is kept.
+    root: OnceLock<PathBuf>,
     entry: Mutex<Option<String>>,
+    /// The namespaces of the makefiles given to `nuke include`.
+    namespaces: Mutex<HashMap<PathBuf, String>>,
+    env: Mutex<Record>,
+    pools: Mutex<HashMap<String, NonZeroUsize>>,
+}
+
+impl NukePlugin {
+    /// The task of the name, whose later definitions override the earlier ones.
+    fn task(&self, name: &str) -> Option<&Task> {
+        self.tasks
+            .iter()
+            .filter(|(_, task)| task.name() == name)
+            .last()
+            .map(|(_, task)| task)
+    }
+
+    /// The tasks in order of definition, leaving out the overridden ones.
+    fn defined_tasks(&self) -> Vec<&Task> {
+        let latest: HashMap<_, _> = self
+            .tasks
+            .iter()
+            .map(|(i, task)| (task.name(), i))
+            .collect();
+
+        self.tasks
+            .iter()
+            .filter(|&(i, task)| latest[task.name()] == i)
+            .map(|(_, task)| task)
+            .collect()
+    }
+
+    /// The public tasks depending on the task, through private ones if need be.
+    fn public_dependents(&self, name: &str) -> Vec<&str> {
+        let tasks = self.defined_tasks();
+        let mut dependents = vec![];
+        let mut visited = HashSet::from_iter([name]);
+        let mut queue = vec![name];
+
+        while let Some(name) = queue.pop() {
+            for task in &tasks {
+                let depends = [task.deps(), task.optional_deps(), task.after()]
+                    .into_iter()
+                    .flatten()
+                    .any(|dname| dname.item == name);
+                if !depends || !visited.insert(task.name()) {
+                    continue;
+                }
+                if task.is_private() {
+                    queue.push(task.name());
+                } else {
+                    dependents.push(task.name());
+                }
+            }
+        }
+
+        dependents
+    }
 }
 
 impl Plugin for NukePlugin {
@@ -26,7 +95,15 @@ impl Plugin for NukePlugin {
         vec![
             Box::new(NukeSchedule),
             Box::new(NukeTask),
+            Box::new(NukeRule),
             Box::new(NukeEntry),
+            Box::new(NukeList),
+            Box::new(NukeClean),
+            Box::new(NukeCacheStats),
+            Box::new(NukeCachePrune),
+            Box::new(NukeInclude),
+            Box::new(NukeEnv),
+            Box::new(NukePool),
         ]
     }
 }
@@ -49,14 +126,66 @@ impl SimplePluginCommand for NukeTask {
         Signature::build(PluginCommand::name(self))
             .input_output_type(Type::Nothing, Type::record())
             .required("name", SyntaxShape::String, "task name")
-            .optional("run", SyntaxShape::Closure(None), "task body")
+            .optional(
+                "run",
+                SyntaxShape::Closure(None),
+                "task body, taking a record of the values returned by its deps",
+            )
             .named(
                 "deps",
+                SyntaxShape::OneOf(vec![
+                    SyntaxShape::List(Box::new(SyntaxShape::String)),
+                    SyntaxShape::Closure(None),
+                ]),
+                "the needed tasks to run first, or a closure listing them when the task is scheduled; those prefixed with `?` are optional",
+                None,
+            )
+            .named(
+                "optional-deps",
                 SyntaxShape::List(Box::new(SyntaxShape::String)),
-                "the needed tasks to run first",
+                "the tasks to run first if they're defined",
+                None,
+            )
+            .named(
+                "after",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "the tasks to run first, without making this task stale by running",
                 None,
             )
             .named("target", SyntaxShape::Filepath, "the built file", None)
+            .named(
+                "clean",
+                SyntaxShape::List(Box::new(SyntaxShape::Filepath)),
+                "the side outputs to remove along with <target> by `nuke clean`",
+                None,
+            )
+            .named(
+                "depfile",
+                SyntaxShape::Filepath,
+                "the Makefile-format file where the run lists more inputs, e.g. by `gcc -MD`",
+                None,
+            )
+            .switch(
+                "no-target-check",
+                "don't fail when a run leaves <target> missing or unchanged",
+                None,
+            )
+            .switch(
+                "restat",
+                "don't make dependents stale when a run leaves <target> as it was",
+                None,
+            )
+            .switch(
+                "keep-failed-target",
+                "keep <target> when a failed run changed it, instead of removing it",
+                None,
+            )
+            .named(
+                "cwd",
+                SyntaxShape::Directory,
+                "the directory to run the task in [default: the directory of make.nu]",
+                None,
+            )
             .named(
                 "files",
                 SyntaxShape::List(Box::new(SyntaxShape::OneOf(vec![
@@ -66,6 +195,81 @@ impl SimplePluginCommand for NukeTask {
                 "the dependent files",
                 None,
             )
+            .named(
+                "exclude",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "patterns in `.gitignore` syntax to drop from the expanded <files>",
+                None,
+            )
+            .switch(
+                "gitignore",
+                "skip the files ignored by `.gitignore` and `.ignore` when expanding <files>",
+                None,
+            )
+            .named(
+                "env",
+                SyntaxShape::Record(vec![]),
+                "the environment variables to run the task with",
+                None,
+            )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "the longest time to wait for the output of the task, which keeps running in the background",
+                None,
+            )
+            .named(
+                "retry",
+                SyntaxShape::Int,
+                "the number of times to rerun the task after it failed",
+                None,
+            )
+            .named(
+                "retry-delay",
+                SyntaxShape::Duration,
+                "the time to wait before each retry",
+                None,
+            )
+            .named(
+                "if",
+                SyntaxShape::Closure(None),
+                "run the task only when this closure returns true",
+                None,
+            )
+            .named(
+                "vars",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "the environment variables whose changes make the task stale",
+                None,
+            )
+            .named(
+                "stamp",
+                SyntaxShape::Closure(None),
+                "the closure whose output changes make the task stale",
+                None,
+            )
+            .named(
+                "pool",
+                SyntaxShape::String,
+                "the pool limiting the concurrency of the task",
+                None,
+            )
+            .named(
+                "weight",
+                SyntaxShape::OneOf(vec![SyntaxShape::Int, SyntaxShape::String]),
+                "the number of job slots the task holds, or `all` [default: 1]",
+                None,
+            )
+            .switch(
+                "private",
+                "run the task only as a dependency and hide it from listings",
+                None,
+            )
+            .switch(
+                "override",
+                "replace the task of the same name defined earlier",
+                None,
+            )
     }
 
     fn run(
@@ -75,17 +279,72 @@ impl SimplePluginCommand for NukeTask {
         call: &EvaluatedCall,
         _input: &Value,
     ) -> Result<Value, LabeledError> {
-        let workdir = engine.get_current_dir()?;
-        let workdir = Path::new(&workdir);
+        let root = root(plugin, engine)?;
+        let basedir = basedir(engine)?;
+        let workdir = match call.get_flag::<PathBuf>("cwd")? {
+            Some(cwd) => basedir.join(cwd),
+            None => basedir,
+        };
+        let workdir = workdir.as_path();
+        let namespace = namespace(plugin, engine)?;
+        let qualify = |name| qualify(namespace.as_deref(), name);
 
-        let name = Spanned::<String>::from_value(call.positional[0].clone())?;
+        let name = qualify(Spanned::<String>::from_value(call.positional[0].clone())?);
+        if let Some(defined) = plugin.task(&name.item)
+            && !call.has_flag("override")?
+        {
+            return Err(LabeledError::new(format!("Duplicate task `{}`", name.item))
+                .with_label("first defined here", defined.name.span)
+                .with_label("defined again here", name.span)
+                .with_help("Pass `--override` to replace the first definition"));
+        }
         let run = call
             .positional
             .get(1)
             .cloned()
             .map(Spanned::<Closure>::from_value)
             .transpose()?;
-        let deps = call.get_flag("deps")?.unwrap_or_default();
+        let condition = call.get_flag("if")?;
+        let vars = call.get_flag("vars")?.unwrap_or_default();
+        let stamp = call.get_flag("stamp")?;
+        let mut optional_deps: Vec<Spanned<String>> =
+            call.get_flag("optional-deps")?.unwrap_or_default();
+        let (mut deps, deps_fn): (Vec<Spanned<String>>, _) = match call.get_flag_value("deps") {
+            Some(Value::Closure { val, internal_span }) => (
+                vec![],
+                Some(Spanned {
+                    item: *val,
+                    span: internal_span,
+                }),
+            ),
+            Some(val) => (FromValue::from_value(val)?, None),
+            None => (vec![], None),
+        };
+        deps.retain(|dname| match dname.item.strip_prefix('?') {
+            Some(name) => {
+                optional_deps.push(Spanned {
+                    item: name.to_owned(),
+                    span: dname.span,
+                });
+                false
+            }
+            None => true,
+        });
+        let deps = deps.into_iter().map(qualify).collect();
+        let optional_deps = optional_deps.into_iter().map(qualify).collect();
+        let after = call
+            .get_flag::<Vec<Spanned<String>>>("after")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(qualify)
+            .collect();
+        let collector = FileCollector::new(
+            workdir,
+            &call
+                .get_flag::<Vec<Spanned<String>>>("exclude")?
+                .unwrap_or_default(),
+            call.has_flag("gitignore")?,
+        )?;
         let files = call
             .get_flag_value("files")
             .map(|vs| {
@@ -93,17 +352,10 @@ impl SimplePluginCommand for NukeTask {
 
                 for v in vs.into_list()? {
                     match v {
-                        Value::String { val, .. } => files.push(workdir.join(val)),
+                        Value::String { val, .. } => files.extend(collector.path(&val)),
                         Value::Glob {
                             val, internal_span, ..
-                        } => glob(&workdir.join(val).to_string_lossy())
-                            .map_err(|e| ShellError::InvalidGlobPattern {
-                                msg: e.msg.into(),
-                                span: internal_span,
-                            })?
-                            .flatten()
-                            .filter(|p| p.is_file())
-                            .for_each(|p| files.push(p)),
+                        } => files.extend(collector.glob(&val, internal_span)?),
                         _ => {
                             return Err(ShellError::TypeMismatch {
                                 err_message: "<file> can only be `string` or `glob`".into(),
@@ -117,14 +369,271 @@ impl SimplePluginCommand for NukeTask {
             })
             .transpose()?
             .unwrap_or_default();
-        let target = call.get_flag("target")?;
+        let target = call
+            .get_flag::<PathBuf>("target")?
+            .map(|target| workdir.join(target));
+        let depfile = call
+            .get_flag::<PathBuf>("depfile")?
+            .map(|depfile| workdir.join(depfile));
+        let clean = call
+            .get_flag::<Vec<PathBuf>>("clean")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|path| workdir.join(path))
+            .collect();
+        let env = call.get_flag("env")?.unwrap_or_default();
+        let timeout = call.get_duration_flag("timeout")?;
+        let pool = call.get_flag("pool")?;
+        let weight = call
+            .get_flag_value("weight")
+            .map(|v| match v {
+                Value::String { ref val, .. } if val == "all" => Ok(Weight::All),
+                Value::Int { val, .. } => usize::try_from(val)
+                    .ok()
+                    .and_then(NonZero::new)
+                    .map(Weight::Slots)
+                    .ok_or_else(|| ShellError::IncorrectValue {
+                        msg: "<weight> should be greater than 0".into(),
+                        val_span: v.span(),
+                        call_span: call.head,
+                    }),
+                _ => Err(ShellError::TypeMismatch {
+                    err_message: "<weight> can only be a positive `int` or `all`".into(),
+                    span: v.span(),
+                }),
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let retry = call.get_flag("retry")?.unwrap_or_default();
+        let retry_delay = call.get_duration_flag("retry-delay")?.unwrap_or_default();
 
-        plugin.tasks.push(Task {
+        let mut task = Task {
             name,
             deps,
+            deps_fn,
+            namespace,
+            after,
+            optional_deps,
+            files,
+            target,
+            clean,
+            depfile,
+            check_target: !call.has_flag("no-target-check")?,
+            keep_failed_target: call.has_flag("keep-failed-target")?,
+            restat: call.has_flag("restat")?,
+            workdir: workdir.to_path_buf(),
+            run,
+            args: vec![],
+            condition,
+            vars,
+            stamp,
+            env,
+            timeout,
+            retry,
+            retry_delay,
+            pool,
+            weight,
+            recipe: String::new(),
+            fingerprint: String::new(),
+            private: call.has_flag("private")?,
+        };
+        task.recipe = recipe(engine, [task.run(), task.stamp()])?;
+        task.fingerprint = fingerprint(&task, root);
+        plugin.tasks.push(task);
+
+        Ok(Value::nothing(call.head))
+    }
+}
+
+/// The cache set up by `$env.NUKE_CACHE_DIR`, limited to `$env.NUKE_CACHE_MAX_SIZE`.
+fn cache(engine: &EngineInterface) -> Result<Option<Cache>, ShellError> {
+    let Some(dir) = engine.get_env_var("NUKE_CACHE_DIR")? else {
+        return Ok(None);
+    };
+    let dir = PathBuf::from(dir.coerce_into_string()?);
+
+    let max_size = match engine.get_env_var("NUKE_CACHE_MAX_SIZE")? {
+        None => Some(Cache::DEFAULT_MAX_SIZE),
+        Some(Value::Filesize { val, .. }) => val.get().try_into().ok(),
+        Some(Value::Int { val, .. }) => val.try_into().ok(),
+        Some(ref val) => val.coerce_str()?.parse().ok(),
+    }
+    .ok_or_else(|| ShellError::GenericError {
+        error: "Invalid NUKE_CACHE_MAX_SIZE".into(),
+        msg: "should be a filesize or a number of bytes".into(),
+        span: None,
+        help: None,
+        inner: vec![],
+    })?;
+
+    Ok(Some(Cache::new(dir, max_size)))
+}
+
+/// The directory of the top-level make.nu, which nu is started in to source it.
+/// Makefiles sourced or included by it have their own directories, so the first of them to
+/// define something can't tell it.
+fn root<'p>(plugin: &'p NukePlugin, engine: &EngineInterface) -> Result<&'p Path, ShellError> {
+    if let Some(root) = plugin.root.get() {
+        return Ok(root);
+    }
+    let dir = PathBuf::from(engine.get_current_dir()?);
+    Ok(plugin.root.get_or_init(|| dir))
+}
+
+/// The directory of the makefile being sourced, or the current one outside of makefiles.
+fn basedir(engine: &EngineInterface) -> Result<PathBuf, ShellError> {
+    // `FILE_PWD` is only present while sourcing a file.
+    match engine.get_env_var("FILE_PWD")? {
+        Some(dir) => Ok(PathBuf::from(dir.coerce_into_string()?)),
+        None => Ok(PathBuf::from(engine.get_current_dir()?)),
+    }
+}
+
+/// The namespace of the makefile being sourced, if it was given to `nuke include`.
+fn namespace(plugin: &NukePlugin, engine: &EngineInterface) -> Result<Option<String>, ShellError> {
+    let Some(file) = engine.get_env_var("CURRENT_FILE")? else {
+        return Ok(None);
+    };
+    let file = PathBuf::from(file.coerce_into_string()?);
+    let file = file.canonicalize().unwrap_or(file);
+
+    Ok(plugin.namespaces.lock().unwrap().get(&file).cloned())
+}
+
+/// Identifies the source of the closures, wherever the makefile is.
+fn recipe<'c>(
+    engine: &EngineInterface,
+    closures: impl IntoIterator<Item = Option<&'c Spanned<Closure>>>,
+) -> Result<String, ShellError> {
+    let mut hasher = blake3::Hasher::new();
+
+    for closure in closures {
+        let source = closure
+            .map(|closure| engine.get_span_contents(closure.span))
+            .transpose()?
+            .unwrap_or_default();
+        hasher.update(&(source.len() as u64).to_le_bytes());
+        hasher.update(&source);
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Identifies the recipe of the task along with the flags affecting its output.
+/// Paths are taken relative to the working directory, itself taken relative to `root`,
+/// so the fingerprint matches across checkouts.
+fn fingerprint(task: &Task, root: &Path) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(task.recipe().as_bytes());
+
+    let relative = |path: &Path| path.strip_prefix(task.workdir()).unwrap_or(path).to_owned();
+    let files: Vec<_> = task.files().iter().map(|file| relative(file)).collect();
+
+    let names = |names: &[Spanned<String>]| -> Vec<String> {
+        names.iter().map(|name| name.item.clone()).collect()
+    };
+    let config = Config::default();
+    let env: Vec<_> = task
+        .env()
+        .iter()
+        .map(|(name, val)| (name, val.to_expanded_string(" ", &config)))
+        .collect();
+    let flags = format!(
+        "{:?}",
+        (
+            names(task.deps()),
+            names(task.optional_deps()),
             files,
+            task.target().map(relative),
+            task.depfile().map(relative),
+            task.workdir().strip_prefix(root).unwrap_or(task.workdir()),
+            env,
+            names(task.vars()),
+        )
+    );
+    hasher.update(flags.as_bytes());
+
+    hasher.finalize().to_hex().to_string()
+}
+
+#[derive(Debug)]
+struct NukeRule;
+
+impl SimplePluginCommand for NukeRule {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke rule"
+    }
+
+    fn description(&self) -> &str {
+        "Define pattern rule building files from others"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .required(
+                "target",
+                SyntaxShape::String,
+                "the pattern of built files, where `%` matches any stem",
+            )
+            .required(
+                "run",
+                SyntaxShape::Closure(Some(vec![SyntaxShape::String, SyntaxShape::String])),
+                "rule body, taking the target and source paths",
+            )
+            .required_named(
+                "from",
+                SyntaxShape::String,
+                "the pattern of source files, where `%` is replaced with the stem",
+                None,
+            )
+            .named(
+                "depfile",
+                SyntaxShape::String,
+                "the pattern of the depfile listing more inputs, see `nuke task --depfile`",
+                None,
+            )
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let root = root(plugin, engine)?;
+        let workdir = basedir(engine)?;
+
+        let target: Spanned<String> = call.req(0)?;
+        if target.item.matches('%').count() != 1 {
+            return Err(LabeledError::new("Invalid rule pattern")
+                .with_label("should contain exactly one `%`", target.span));
+        }
+        let run: Spanned<Closure> = call.req(1)?;
+        let source: Spanned<String> = call
+            .get_flag("from")?
+            .ok_or_else(|| LabeledError::new("Missing <from> of the rule"))?;
+
+        let recipe = recipe(engine, [Some(&run)])?;
+        let depfile: Option<String> = call.get_flag("depfile")?;
+        let namespace = namespace(plugin, engine)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(recipe.as_bytes());
+        let dir = workdir.strip_prefix(root).unwrap_or(&workdir);
+        hasher.update(format!("{:?}", (&target.item, &source.item, &depfile, dir)).as_bytes());
+
+        plugin.rules.push(Rule {
             target,
+            source,
+            depfile,
+            namespace,
+            workdir,
             run,
+            recipe,
+            fingerprint: hasher.finalize().to_hex().to_string(),
+            instances: boxcar::Vec::new(),
         });
 
         Ok(Value::nothing(call.head))
@@ -147,6 +656,7 @@ impl SimplePluginCommand for NukeSchedule {
 
     fn signature(&self) -> Signature {
         Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Nothing, Type::table())
             .optional("name", SyntaxShape::String, "task name")
             .named(
                 "jobs",
@@ -154,6 +664,34 @@ impl SimplePluginCommand for NukeSchedule {
                 "Number of parallel jobs [default: the number of cores]",
                 Some('j'),
             )
+            .named(
+                "timeout",
+                SyntaxShape::Duration,
+                "The longest time to wait for the output of a task, unless it sets its own; the task itself isn't interrupted",
+                None,
+            )
+            .named(
+                "with",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Optional dependencies to run even with `--no-optional`",
+                None,
+            )
+            .named(
+                "without",
+                SyntaxShape::List(Box::new(SyntaxShape::String)),
+                "Optional dependencies not to run",
+                None,
+            )
+            .switch(
+                "no-optional",
+                "Run no optional dependencies but those passed to `--with`",
+                None,
+            )
+            .switch(
+                "dry-run",
+                "Print the tasks to run without running them",
+                Some('n'),
+            )
     }
 
     fn run(
@@ -182,20 +720,69 @@ impl SimplePluginCommand for NukeSchedule {
                         Pass it when running `nuke schedule <name>` or mark it with `nuke entry <name>`"));
             };
 
-            plugin
-                .tasks
-                .iter()
-                .find_map(|(_, task)| (task.name() == name).then_some(task))
-                .ok_or_else(|| LabeledError::new(format!("task `{name}` not found")))?
+            let task = plugin
+                .task(name)
+                .ok_or_else(|| LabeledError::new(format!("task `{name}` not found")))?;
+            if task.is_private() && !call.positional.is_empty() {
+                let dependents = plugin.public_dependents(name);
+                let help = if dependents.is_empty() {
+                    "No public task depends on it".to_owned()
+                } else {
+                    let dependents: Vec<_> =
+                        dependents.iter().map(|name| format!("`{name}`")).collect();
+                    format!("Run a task depending on it: {}", dependents.join(", "))
+                };
+                return Err(LabeledError::new(format!("Task `{name}` is private"))
+                    .with_label("defined as private here", task.name.span)
+                    .with_help(help));
+            }
+            task
         };
 
-        let mut sched = Scheduler::new(jobs);
+        let root = root(plugin, engine)?;
 
-        TaskGraph::new(plugin.tasks.iter().map(|(_, task)| task), &mut sched).submit(task)?;
+        let mut sched = Scheduler::new(jobs)
+            .with_state(State::load(root))
+            .with_env(plugin.env.lock().unwrap().clone())
+            .with_timeout(call.get_duration_flag("timeout")?)
+            .with_pools(plugin.pools.lock().unwrap().clone())
+            .with_cache(cache(engine)?)
+            .with_dry_run(call.has_flag("dry-run")?);
 
-        sched.run(engine)?;
+        let optional = OptionalDeps {
+            all: !call.has_flag("no-optional")?,
+            with: call
+                .get_flag::<Vec<String>>("with")?
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            without: call
+                .get_flag::<Vec<String>>("without")?
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+        };
 
-        Ok(Value::nothing(call.head))
+        let evaluator = Evaluator::new(engine, call.head)?;
+        let cwd = engine.get_current_dir()?;
+        let res = (|| -> Result<_, LabeledError> {
+            TaskGraph::new(
+                plugin.tasks.iter().map(|(_, task)| task),
+                &mut sched,
+                &evaluator,
+            )
+            .with_rules(plugin.rules.iter().map(|(_, rule)| rule))
+            .with_optional_deps(optional)
+            .submit(task)?;
+            Ok(sched.run(&evaluator)?)
+        })();
+        evaluator.cd(Path::new(&cwd), call.head)?;
+        let reports = res?
+            .into_iter()
+            .map(|report| report.into_value(call.head))
+            .collect();
+
+        Ok(Value::list(reports, call.head))
     }
 }
 
@@ -221,6 +808,431 @@ impl SimplePluginCommand for NukeEntry {
         )
     }
 
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let name = qualify(namespace(plugin, engine)?.as_deref(), call.req(0)?);
+        *plugin.entry.lock().unwrap() = Some(name.item);
+
+        Ok(Value::nothing(call.head))
+    }
+}
+
+#[derive(Debug)]
+struct NukeClean;
+
+impl SimplePluginCommand for NukeClean {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke clean"
+    }
+
+    fn description(&self) -> &str {
+        "Remove targets of task and its dependencies, or of all tasks"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .input_output_type(Type::Nothing, Type::List(Box::new(Type::String)))
+            .optional("name", SyntaxShape::String, "task name")
+            .switch(
+                "dry-run",
+                "List the paths to remove without removing them",
+                Some('n'),
+            )
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let tasks = plugin.defined_tasks();
+        let rules = || plugin.rules.iter().map(|(_, rule)| rule);
+        let evaluator = Evaluator::new(engine, call.head)?;
+        let cwd = engine.get_current_dir()?;
+
+        // Submitting every task also brings in those instantiated from rules.
+        let mut all = Scheduler::new(None);
+        let mut some = Scheduler::new(None);
+        let res = (|| -> Result<_, LabeledError> {
+            let mut graph =
+                TaskGraph::new(tasks.iter().copied(), &mut all, &evaluator).with_rules(rules());
+            for &task in &tasks {
+                graph.submit(task)?;
+            }
+
+            let Some(name) = call.opt::<Spanned<String>>(0)? else {
+                return Ok(false);
+            };
+            let task = plugin.task(&name.item).ok_or_else(|| {
+                LabeledError::new(format!("task `{}` not found", name.item))
+                    .with_label("Task not found", name.span)
+            })?;
+            TaskGraph::new(tasks.iter().copied(), &mut some, &evaluator)
+                .with_rules(rules())
+                .submit(task)?;
+            Ok(true)
+        })();
+        evaluator.cd(Path::new(&cwd), call.head)?;
+        // The tasks only ordered before the requested one keep their targets.
+        let cleaned = if res? {
+            some.needed_tasks()
+        } else {
+            all.tasks().to_vec()
+        };
+
+        let targets: HashSet<_> = all
+            .tasks()
+            .iter()
+            .filter_map(|task| task.target())
+            .collect();
+        let mut removed = vec![];
+        for task in cleaned {
+            for path in task
+                .target()
+                .into_iter()
+                .chain(task.clean().iter().map(PathBuf::as_path))
+            {
+                // Removing a directory takes the files in it along.
+                let input = all.tasks().iter().find_map(|other| {
+                    let file = other
+                        .files()
+                        .iter()
+                        .find(|file| file.starts_with(path) && !targets.contains(file.as_path()))?;
+                    Some((other, file))
+                });
+                if let Some((other, file)) = input {
+                    return Err(LabeledError::new(format!(
+                        "Refusing to remove `{}`",
+                        path.display()
+                    ))
+                    .with_label("declared as output here", task.name.span)
+                    .with_label(
+                        format!("but `{}` is listed in <files> here", file.display()),
+                        other.name.span,
+                    )
+                    .with_help("Only files built by some task can be removed"));
+                }
+                if path.symlink_metadata().is_ok() && !removed.contains(&path) {
+                    removed.push(path);
+                }
+            }
+        }
+
+        if !call.has_flag("dry-run")? {
+            for path in &removed {
+                let res = if path.is_dir() {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                };
+                res.map_err(|e| {
+                    ShellError::Io(IoError::new_with_additional_context(
+                        e.kind(),
+                        call.head,
+                        path.to_path_buf(),
+                        "Failed to remove target",
+                    ))
+                })?;
+            }
+        }
+
+        let removed = removed
+            .into_iter()
+            .map(|path| Value::string(path.to_string_lossy(), call.head))
+            .collect();
+        Ok(Value::list(removed, call.head))
+    }
+}
+
+#[derive(Debug)]
+struct NukeCacheStats;
+
+impl SimplePluginCommand for NukeCacheStats {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke cache stats"
+    }
+
+    fn description(&self) -> &str {
+        "Show size of target cache set by NUKE_CACHE_DIR"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self)).input_output_type(Type::Nothing, Type::record())
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let cache = require_cache(engine)?;
+        let stats = cache
+            .stats()
+            .map_err(|e| cache_error(e, &cache, "Failed to read the cache"))?;
+        let span = call.head;
+
+        Ok(Value::record(
+            record! {
+                "dir" => Value::string(cache.dir().to_string_lossy(), span),
+                "entries" => Value::int(stats.entries as i64, span),
+                "size" => Value::filesize(stats.size as i64, span),
+                "max_size" => Value::filesize(cache.max_size() as i64, span),
+            },
+            span,
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct NukeCachePrune;
+
+impl SimplePluginCommand for NukeCachePrune {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke cache prune"
+    }
+
+    fn description(&self) -> &str {
+        "Evict least recently used targets until cache fits in NUKE_CACHE_MAX_SIZE"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self)).input_output_type(Type::Nothing, Type::record())
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let cache = require_cache(engine)?;
+        let evicted = cache
+            .prune()
+            .map_err(|e| cache_error(e, &cache, "Failed to prune the cache"))?;
+        let span = call.head;
+
+        Ok(Value::record(
+            record! {
+                "evicted" => Value::int(evicted.entries as i64, span),
+                "freed" => Value::filesize(evicted.size as i64, span),
+            },
+            span,
+        ))
+    }
+}
+
+fn require_cache(engine: &EngineInterface) -> Result<Cache, LabeledError> {
+    cache(engine)?.ok_or_else(|| {
+        LabeledError::new("No cache set up")
+            .with_help("Set `$env.NUKE_CACHE_DIR` to the directory of the cache")
+    })
+}
+
+fn cache_error(e: std::io::Error, cache: &Cache, context: &str) -> ShellError {
+    ShellError::Io(IoError::new_internal_with_path(
+        e.kind(),
+        context,
+        nu_protocol::location!(),
+        cache.dir().to_path_buf(),
+    ))
+}
+
+#[derive(Debug)]
+struct NukeList;
+
+impl SimplePluginCommand for NukeList {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke list"
+    }
+
+    fn description(&self) -> &str {
+        "List public tasks"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self)).input_output_type(Type::Nothing, Type::table())
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let span = call.head;
+        let names = |names: &[Spanned<String>]| {
+            Value::list(
+                names
+                    .iter()
+                    .map(|name| Value::string(&name.item, span))
+                    .collect(),
+                span,
+            )
+        };
+
+        let tasks = plugin
+            .defined_tasks()
+            .into_iter()
+            .filter(|task| !task.is_private())
+            .map(|task| {
+                Value::record(
+                    record! {
+                        "name" => Value::string(task.name(), span),
+                        "deps" => names(task.deps()),
+                        "env" => Value::record(task.env().clone(), span),
+                    },
+                    span,
+                )
+            })
+            .collect();
+
+        Ok(Value::list(tasks, span))
+    }
+}
+
+#[derive(Debug)]
+struct NukeInclude;
+
+impl SimplePluginCommand for NukeInclude {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke include"
+    }
+
+    fn description(&self) -> &str {
+        "Namespace tasks of makefile, which should be sourced right after"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .required("file", SyntaxShape::Filepath, "makefile to include")
+            .named(
+                "prefix",
+                SyntaxShape::String,
+                "the namespace of its tasks [default: the name of its directory]",
+                None,
+            )
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        root(plugin, engine)?;
+        let basedir = basedir(engine)?;
+
+        let file: Spanned<PathBuf> = call.req(0)?;
+        let path = basedir.join(&file.item).canonicalize().map_err(|e| {
+            LabeledError::new("Failed to include makefile").with_label(e.to_string(), file.span)
+        })?;
+        let prefix = match call.get_flag::<String>("prefix")? {
+            Some(prefix) => prefix,
+            None => path
+                .parent()
+                .and_then(Path::file_name)
+                .map(|name| name.to_string_lossy().into_owned())
+                .ok_or_else(|| {
+                    LabeledError::new("Missing <prefix> of the makefile")
+                        .with_label("its directory has no name", file.span)
+                })?,
+        };
+        let prefix = match namespace(plugin, engine)? {
+            Some(namespace) => format!("{namespace}:{prefix}"),
+            None => prefix,
+        };
+
+        plugin.namespaces.lock().unwrap().insert(path, prefix);
+
+        Ok(Value::nothing(call.head))
+    }
+}
+
+#[derive(Debug)]
+struct NukeEnv;
+
+impl SimplePluginCommand for NukeEnv {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke env"
+    }
+
+    fn description(&self) -> &str {
+        "Set default environment variables of tasks"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self)).required(
+            "env",
+            SyntaxShape::Record(vec![]),
+            "environment variables",
+        )
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let mut env = plugin.env.lock().unwrap();
+        for (name, val) in call.req::<Record>(0)? {
+            env.insert(name, val);
+        }
+
+        Ok(Value::nothing(call.head))
+    }
+}
+
+#[derive(Debug)]
+struct NukePool;
+
+impl SimplePluginCommand for NukePool {
+    type Plugin = NukePlugin;
+
+    fn name(&self) -> &str {
+        "nuke pool"
+    }
+
+    fn description(&self) -> &str {
+        "Define pool limiting the concurrency of its tasks"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(PluginCommand::name(self))
+            .required("name", SyntaxShape::String, "pool name")
+            .required_named(
+                "size",
+                SyntaxShape::Int,
+                "the most tasks of the pool to run at once",
+                None,
+            )
+    }
+
     fn run(
         &self,
         plugin: &Self::Plugin,
@@ -228,7 +1240,12 @@ impl SimplePluginCommand for NukeEntry {
         call: &EvaluatedCall,
         _input: &Value,
     ) -> Result<Value, LabeledError> {
-        *plugin.entry.lock().unwrap() = Some(call.positional[0].as_str()?.into());
+        let name = call.req(0)?;
+        let size = call.get_flag::<usize>("size")?.unwrap_or_default();
+        let size = NonZero::new(size)
+            .ok_or_else(|| LabeledError::new("<size> should be greater than 0"))?;
+
+        plugin.pools.lock().unwrap().insert(name, size);
 
         Ok(Value::nothing(call.head))
     }
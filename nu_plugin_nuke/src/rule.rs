@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nu_protocol::engine::Closure;
+use nu_protocol::{Record, Spanned, Value};
+
+use crate::Task;
+use crate::task::qualify;
+
+/// A pattern rule, building the files matching `target` from those matching `source`,
+/// where `%` stands for the same stem in both.
+#[derive(Debug)]
+pub struct Rule {
+    pub(crate) target: Spanned<String>,
+    pub(crate) source: Spanned<String>,
+    pub(crate) depfile: Option<String>,
+    /// The namespace given by `nuke include` to the makefile defining the rule.
+    pub(crate) namespace: Option<String>,
+    pub(crate) workdir: PathBuf,
+    pub(crate) run: Spanned<Closure>,
+    pub(crate) recipe: String,
+    pub(crate) fingerprint: String,
+    /// The tasks instantiated from the rule, kept as long as the rule.
+    pub(crate) instances: boxcar::Vec<Task>,
+}
+
+impl Rule {
+    /// The path named by a dependency, if it's in the namespace of the rule.
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        let path = match &self.namespace {
+            Some(namespace) => name.strip_prefix(namespace.as_str())?.strip_prefix(':')?,
+            None => name,
+        };
+        Some(self.workdir.join(path))
+    }
+
+    /// The stem of `path` if it matches the target pattern.
+    pub fn stem<'p>(&self, path: &'p Path) -> Option<&'p str> {
+        let path = path.strip_prefix(&self.workdir).ok()?.to_str()?;
+        let (prefix, suffix) = self.target.item.split_once('%')?;
+
+        path.strip_prefix(prefix)?
+            .strip_suffix(suffix)
+            .filter(|stem| !stem.is_empty())
+    }
+
+    /// The target path relative to the working directory.
+    pub fn target(&self, stem: &str) -> String {
+        self.target.item.replacen('%', stem, 1)
+    }
+
+    /// The name of the instantiated task, its target put into the namespace of the rule.
+    pub fn name(&self, stem: &str) -> String {
+        let target = Spanned {
+            item: self.target(stem),
+            span: self.target.span,
+        };
+        qualify(self.namespace.as_deref(), target).item
+    }
+
+    pub fn source(&self, stem: &str) -> PathBuf {
+        self.workdir.join(self.source.item.replacen('%', stem, 1))
+    }
+
+    pub fn instantiate(&self, stem: &str) -> &Task {
+        let span = self.target.span;
+        let target = self.target(stem);
+        let source = self.source.item.replacen('%', stem, 1);
+
+        let id = self.instances.push(Task {
+            name: Spanned {
+                item: self.name(stem),
+                span,
+            },
+            deps: vec![],
+            deps_fn: None,
+            namespace: self.namespace.clone(),
+            after: vec![],
+            optional_deps: vec![],
+            files: vec![self.workdir.join(&source)],
+            target: Some(self.workdir.join(&target)),
+            clean: vec![],
+            depfile: self
+                .depfile
+                .as_ref()
+                .map(|depfile| self.workdir.join(depfile.replacen('%', stem, 1))),
+            check_target: true,
+            keep_failed_target: false,
+            restat: false,
+            workdir: self.workdir.clone(),
+            run: Some(self.run.clone()),
+            args: vec![Value::string(target, span), Value::string(source, span)],
+            condition: None,
+            vars: vec![],
+            stamp: None,
+            env: Record::new(),
+            timeout: None,
+            retry: 0,
+            retry_delay: Duration::ZERO,
+            pool: None,
+            weight: Default::default(),
+            recipe: self.recipe.clone(),
+            fingerprint: self.fingerprint.clone(),
+            private: false,
+        });
+        &self.instances[id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use nu_protocol::engine::Closure;
+    use nu_protocol::{BlockId, Span, Spanned};
+
+    use super::Rule;
+    use crate::task::qualify;
+
+    fn spanned<T>(item: T) -> Spanned<T> {
+        Spanned {
+            item,
+            span: Span::test_data(),
+        }
+    }
+
+    fn rule(target: &str, source: &str, namespace: Option<&str>) -> Rule {
+        Rule {
+            target: spanned(target.to_owned()),
+            source: spanned(source.to_owned()),
+            depfile: None,
+            namespace: namespace.map(str::to_owned),
+            workdir: PathBuf::from("/project"),
+            run: spanned(Closure {
+                block_id: BlockId::new(0),
+                captures: vec![],
+            }),
+            recipe: String::new(),
+            fingerprint: String::new(),
+            instances: boxcar::Vec::new(),
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let rule = rule("build/%.o", "src/%.c", None);
+        assert_eq!(
+            rule.path("build/main.o").as_deref(),
+            Some(Path::new("/project/build/main.o"))
+        );
+        assert_eq!(rule.stem(Path::new("/project/build/main.o")), Some("main"));
+        assert_eq!(rule.stem(Path::new("/project/build/a/b.o")), Some("a/b"));
+        assert_eq!(rule.stem(Path::new("/project/src/main.c")), None);
+        assert_eq!(rule.stem(Path::new("/elsewhere/build/main.o")), None);
+        assert_eq!(rule.name("main"), "build/main.o");
+        assert_eq!(rule.source("main"), Path::new("/project/src/main.c"));
+    }
+
+    #[test]
+    fn namespaced_paths() {
+        let rule = rule("build/%.o", "src/%.c", Some("web"));
+        let path = rule.path("web:build/main.o").unwrap();
+        assert_eq!(path, Path::new("/project/build/main.o"));
+        assert_eq!(rule.stem(&path), Some("main"));
+        assert_eq!(rule.name("main"), "web:build/main.o");
+
+        assert_eq!(rule.path("build/main.o"), None);
+        assert_eq!(rule.path("webapp:build/main.o"), None);
+        assert_eq!(rule.path("api:build/main.o"), None);
+    }
+
+    #[test]
+    fn rooted_names() {
+        // A dependency of a task in `web` on a file built by a rule in the root namespace.
+        let name = qualify(Some("web"), spanned(":build/main.o".to_owned())).item;
+        assert_eq!(rule("build/%.o", "src/%.c", Some("web")).path(&name), None);
+
+        let root = rule("build/%.o", "src/%.c", None);
+        let path = root.path(&name).unwrap();
+        assert_eq!(root.stem(&path), Some("main"));
+        assert_eq!(root.name("main"), name);
+    }
+
+    #[test]
+    fn empty_stems() {
+        let objects = rule("build/%.o", "src/%.c", None);
+        assert_eq!(objects.stem(Path::new("/project/build/.o")), None);
+        assert_eq!(objects.stem(Path::new("/project/build/")), None);
+
+        let anything = rule("%", "src/%", None);
+        assert_eq!(anything.stem(Path::new("/project")), None);
+        assert_eq!(anything.stem(Path::new("/project/main")), Some("main"));
+    }
+
+    #[test]
+    fn chained_rules() {
+        let objects = rule("build/%.o", "gen/%.c", Some("web"));
+        let parsers = rule("gen/%.c", "src/%.y", Some("web"));
+
+        let path = objects.path("web:build/parser.o").unwrap();
+        let stem = objects.stem(&path).unwrap();
+        let source = objects.source(stem);
+        assert_eq!(source, Path::new("/project/gen/parser.c"));
+        assert_eq!(objects.stem(&source), None);
+
+        let stem = parsers.stem(&source).unwrap();
+        assert_eq!(parsers.name(stem), "web:gen/parser.c");
+        assert_eq!(parsers.source(stem), Path::new("/project/src/parser.y"));
+    }
+
+    #[test]
+    fn instances() {
+        let rule = rule("build/%.o", "src/%.c", Some("web"));
+        let task = rule.instantiate("main");
+        assert_eq!(task.name(), "web:build/main.o");
+        assert_eq!(task.namespace(), Some("web"));
+        assert_eq!(task.target(), Some(Path::new("/project/build/main.o")));
+        assert_eq!(task.files(), [Path::new("/project/src/main.c")]);
+    }
+}
@@ -1,145 +1,922 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
 use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::thread::{self, Thread};
+use std::time::{Duration, SystemTime};
 
 use ahash::HashMap;
-use nu_plugin::EngineInterface;
-use nu_protocol::{ShellError, Spanned, engine::Closure};
+use chrono::{DateTime, Local};
+use nu_protocol::shell_error::io::IoError;
+use nu_protocol::{Config, Record, ShellError, Span, Value, record};
 
-use crate::Task;
+use crate::cache::Cache;
+use crate::depfile;
+use crate::eval::{self, Evaluator};
+use crate::state::{Restat, State};
 use crate::utils::collections::SlotVec;
+use crate::utils::path::PathExt;
+use crate::utils::time::humanize;
+use crate::{Task, Weight};
 
 #[derive(Debug)]
 pub struct Scheduler<'a> {
     tasks: Vec<&'a Task>,
-    adj_list: Vec<Vec<TaskId>>,
+    adj_list: Vec<Vec<(TaskId, DepKind)>>,
     in_degrees: Vec<usize>,
+    /// The latest timestamp among the fresh dependencies of each task.
+    latest_dep_timestamp: HashMap<usize, SystemTime>,
+    /// Whether any dependency of each task has run.
+    dirty: Vec<bool>,
+    /// The current values of `--vars` and `--stamp` of each task.
+    stamps: Vec<BTreeMap<String, String>>,
+    /// The values returned by the dependencies of each task, keyed by their names.
+    inputs: Vec<Record>,
+    /// How each task went, missing for those not run yet.
+    reports: Vec<Option<Report>>,
+    state: State,
     jobs: usize,
+    env: Record,
+    timeout: Option<Duration>,
+    /// The free slots of each pool.
+    pools: HashMap<String, usize>,
+    cache: Option<Cache>,
+    dry_run: bool,
 }
 
+/// The pool whose tasks own the terminal: they hold all job slots while running,
+/// so no other task prints meanwhile.
+pub const CONSOLE_POOL: &str = "console";
+
 impl<'a> Scheduler<'a> {
     pub fn new(jobs: Option<NonZeroUsize>) -> Self {
         Self {
             tasks: vec![],
             adj_list: vec![],
             in_degrees: vec![],
+            latest_dep_timestamp: HashMap::default(),
+            dirty: vec![],
+            stamps: vec![],
+            inputs: vec![],
+            reports: vec![],
+            state: State::default(),
             jobs: jobs.map(NonZeroUsize::get).unwrap_or_else(num_cpus::get),
+            env: Record::new(),
+            timeout: None,
+            pools: HashMap::from_iter([(CONSOLE_POOL.to_owned(), 1)]),
+            cache: None,
+            dry_run: false,
+        }
+    }
+
+    /// Default environment variables of all tasks.
+    pub fn with_env(mut self, env: Record) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Default timeout of all tasks.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Pools limiting how many of their tasks run at once.
+    pub fn with_pools(mut self, pools: impl IntoIterator<Item = (String, NonZeroUsize)>) -> Self {
+        self.pools
+            .extend(pools.into_iter().map(|(name, size)| (name, size.get())));
+        self
+    }
+
+    /// The state recorded by previous runs, which is updated and saved by this run.
+    pub fn with_state(mut self, state: State) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// The cache to restore targets from, and store them into.
+    pub fn with_cache(mut self, cache: Option<Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The submitted tasks, each after its dependencies.
+    pub fn tasks(&self) -> &[&'a Task] {
+        &self.tasks
+    }
+
+    /// The submitted tasks that the last one needs through normal dependencies,
+    /// leaving out those only ordered before it with `--after`.
+    pub fn needed_tasks(&self) -> Vec<&'a Task> {
+        let mut needed = vec![false; self.tasks.len()];
+        if let Some(last) = needed.last_mut() {
+            *last = true;
+        }
+        // Dependents always come after their dependencies.
+        for id in (0..self.tasks.len()).rev() {
+            needed[id] |= self.adj_list[id]
+                .iter()
+                .any(|&(TaskId(next), kind)| kind == DepKind::Normal && needed[next]);
         }
+
+        let tasks = self.tasks.iter().zip(needed);
+        tasks
+            .filter_map(|(&task, needed)| needed.then_some(task))
+            .collect()
     }
 
-    pub fn add_task(&mut self, task: &'a Task, deps: &[TaskId]) -> TaskId {
+    pub fn add_task(&mut self, task: &'a Task, deps: &[(TaskId, DepKind)]) -> TaskId {
         let id = TaskId(self.tasks.len());
         self.tasks.push(task);
         self.adj_list.push(Vec::new());
         self.in_degrees.push(deps.len());
-        for &TaskId(dep) in deps {
-            self.adj_list[dep].push(id);
+        self.dirty.push(false);
+        let mut inputs = Record::new();
+        for &(TaskId(dep), kind) in deps {
+            self.adj_list[dep].push((id, kind));
+            if kind == DepKind::Normal {
+                inputs.insert(self.tasks[dep].name(), Value::nothing(task.name.span));
+            }
         }
+        self.inputs.push(inputs);
+        self.reports.push(None);
         id
     }
 
-    pub fn run(mut self, engine: &EngineInterface) -> Result<(), ShellError> {
-        let mut run_queue = VecDeque::new();
-        let mut maybe_skip_queue = VecDeque::new();
-
-        for (id, &in_degree) in self.in_degrees.iter().enumerate() {
-            if in_degree == 0 {
-                if let Some(timestamp) = self.tasks[id].cached_at() {
-                    maybe_skip_queue.push_front((TaskId(id), timestamp));
-                } else {
-                    run_queue.push_front(TaskId(id));
-                }
+    /// A failed task stops the run, cancelling those not started yet.
+    pub fn run(mut self, evaluator: &Evaluator) -> Result<Vec<Report>, ShellError> {
+        for task in &self.tasks {
+            if let Some(pool) = task.pool()
+                && !self.pools.contains_key(&pool.item)
+            {
+                return Err(ShellError::GenericError {
+                    error: format!("Pool `{}` not found", pool.item),
+                    msg: "undefined pool".into(),
+                    span: Some(pool.span),
+                    help: Some("Define it with `nuke pool <name> --size <n>`".into()),
+                    inner: vec![],
+                });
             }
         }
 
-        let mut latest_dep_timestamp = HashMap::default();
-        while let Some((TaskId(dep_id), dep_timestamp)) = maybe_skip_queue.pop_back() {
-            for &TaskId(id) in &self.adj_list[dep_id] {
-                self.in_degrees[id] -= 1;
-                let t = latest_dep_timestamp.entry(id).or_insert(dep_timestamp);
-                *t = (*t).max(dep_timestamp);
-                if self.in_degrees[id] == 0 {
-                    match self.tasks[id].cached_at() {
-                        Some(timestamp) if latest_dep_timestamp[&id] <= timestamp => {
-                            maybe_skip_queue.push_back((TaskId(id), timestamp));
-                        }
-                        _ => run_queue.push_front(TaskId(id)),
-                    }
-                }
+        self.stamps = (0..self.tasks.len())
+            .map(|id| self.current_stamps(evaluator, id))
+            .collect::<Result<_, _>>()?;
+
+        let mut run_queue = VecDeque::new();
+        for id in 0..self.tasks.len() {
+            if self.in_degrees[id] == 0 {
+                self.ready(id, &mut run_queue);
             }
         }
 
         let thread_token = thread::current();
-        thread::scope(|sc| -> Result<(), ShellError> {
+        thread::scope(|sc| {
             let mut free_slots = self.jobs;
             let mut run_set = SlotVec::default();
+            let mut overtaken = HashMap::default();
+            let mut failed = false;
 
             loop {
-                while free_slots > 0 && !run_queue.is_empty() {
-                    let TaskId(id) = run_queue.pop_back().unwrap();
-                    let prompt = self.tasks[id].name();
-                    let run = self.tasks[id].run();
+                while free_slots > 0 && !failed {
+                    let Some(pos) = self.next_runnable(&run_queue, free_slots, &mut overtaken)
+                    else {
+                        break;
+                    };
+                    let TaskId(id) = run_queue.remove(pos).unwrap();
+                    let task = self.tasks[id];
+                    if let Some(pool) = task.pool() {
+                        *self.pools.get_mut(&pool.item).unwrap() -= 1;
+                    }
+                    let env = self.task_env(self.tasks[id]);
+                    let input = std::mem::take(&mut self.inputs[id]);
+                    let discovered = self
+                        .state
+                        .task(task.name())
+                        .map(|state| state.discovered.clone())
+                        .unwrap_or_default();
+                    let timeout = task.timeout().or(self.timeout);
 
                     struct Capture<'scope> {
                         id: usize,
-                        prompt: &'scope str,
-                        run: Option<&'scope Spanned<Closure>>,
+                        task: &'scope Task,
+                        env: Record,
+                        input: Record,
+                        stamps: BTreeMap<String, String>,
+                        discovered: Vec<PathBuf>,
+                        timeout: Option<Duration>,
+                        cache: Option<Cache>,
+                        dry_run: bool,
                         thread_token: &'scope Thread,
                     }
 
                     let stask = Capture {
                         id,
-                        prompt,
-                        run,
+                        task,
+                        env,
+                        input,
+                        stamps: self.stamps[id].clone(),
+                        discovered,
+                        timeout,
+                        cache: self.cache.clone(),
+                        dry_run: self.dry_run,
                         thread_token: &thread_token,
                     };
 
-                    run_set.insert(sc.spawn(move || -> Result<usize, ShellError> {
-                        let Capture {
-                            id,
-                            prompt,
-                            run,
-                            thread_token,
-                        } = stask;
-
-                        println!("Running task `{prompt}`");
-                        if let Some(run) = run {
-                            engine
-                                .eval_closure(run, vec![], None)
-                                .inspect_err(|_| thread_token.unpark())?;
-                        }
-                        thread_token.unpark();
-                        Ok(id)
-                    }));
-                    free_slots -= 1;
+                    run_set.insert(sc.spawn(
+                        move || -> (usize, SystemTime, usize, Result<(Outcome, Value), ShellError>) {
+                            let Capture {
+                                id,
+                                task,
+                                env,
+                                input,
+                                stamps,
+                                discovered,
+                                timeout,
+                                cache,
+                                dry_run,
+                                thread_token,
+                            } = stask;
+
+                            let start = SystemTime::now();
+                            let mut attempts = 0;
+                            let res = (|| {
+                                let nothing = Value::nothing(task.name.span);
+                                if !condition_holds(evaluator, task, &env, timeout)? {
+                                    println!("Task `{}` skipped (condition)", task.name());
+                                    return Ok((Outcome::Skipped, nothing));
+                                }
+
+                                if dry_run {
+                                    print_plan(task, &env, timeout);
+                                    Ok((Outcome::Ran, nothing))
+                                } else {
+                                    // Only tasks whose inputs all exist and are all known can
+                                    // be cached: until a run reads its depfile, a task would be
+                                    // keyed by its `--files` alone, whatever they include.
+                                    let known = task.depfile().is_none() || !discovered.is_empty();
+                                    let cached = cache.as_ref().filter(|_| known);
+                                    let cached = cached.and_then(|cache| {
+                                        let key =
+                                            Cache::key(task, &discovered, &stamps, &input).ok()?;
+                                        Some((cache, key))
+                                    });
+                                    let (n, res) =
+                                        build(evaluator, task, env, input, timeout, cached);
+                                    attempts = n;
+                                    res
+                                }
+                            })();
+                            thread_token.unpark();
+                            (id, start, attempts, res)
+                        },
+                    ));
+                    free_slots -= self.weight(id);
                 }
 
                 if !run_set.is_empty() {
                     thread::park();
-                } else if run_queue.is_empty() {
-                    break Ok(());
+                } else if run_queue.is_empty() || failed {
+                    break;
                 }
 
-                for res in run_set
+                for (id, start, attempts, res) in run_set
                     .drain(|task| task.is_finished())
                     .map(|task| task.join().unwrap())
                 {
-                    let id = res?;
-                    free_slots += 1;
+                    free_slots += self.weight(id);
+                    if let Some(pool) = self.tasks[id].pool() {
+                        *self.pools.get_mut(&pool.item).unwrap() += 1;
+                    }
 
-                    for &TaskId(next) in &self.adj_list[id] {
-                        self.in_degrees[next] -= 1;
-                        if self.in_degrees[next] == 0 {
-                            run_queue.push_front(TaskId(next));
+                    let mut report = Report::new(self.tasks[id], Status::Ran);
+                    report.start = Some(start);
+                    report.duration = Some(start.elapsed().unwrap_or_default());
+                    report.attempts = (attempts > 0).then_some(attempts);
+                    let (outcome, output) = match res {
+                        Ok(res) => res,
+                        Err(error) => {
+                            if eval::timed_out(&error) && !self.dry_run {
+                                // The abandoned closure may still write the target once it was
+                                // discarded, so it mustn't pass for up to date next time.
+                                self.state.update(self.tasks[id].name(), |state| {
+                                    state.fingerprint = None
+                                });
+                            }
+                            report.status = Status::Failed;
+                            report.error = Some(error);
+                            self.reports[id] = Some(report);
+                            failed = true;
+                            continue;
                         }
+                    };
+                    if let Outcome::Skipped = outcome {
+                        report.status = Status::Skipped;
+                        report.reason = Some("condition");
+                    } else if let Outcome::Restored = outcome {
+                        report.status = Status::Skipped;
+                        report.reason = Some("cached");
+                    } else if self.dry_run {
+                        report.status = Status::Skipped;
+                        report.reason = Some("dry run");
                     }
+                    self.reports[id] = Some(report);
+
+                    if let Outcome::Ran | Outcome::Unchanged(_) | Outcome::Restored = outcome
+                        && !self.dry_run
+                    {
+                        let task = self.tasks[id];
+                        let stamps = self.stamps[id].clone();
+                        let ran = matches!(outcome, Outcome::Ran | Outcome::Unchanged(_));
+                        let discovered = if ran { read_depfile(task) } else { None };
+                        // Only the outputs handed to dependents are kept, for the runs skipping
+                        // the task, lest the state fill up with build logs.
+                        let consumed = (task.target().is_some() || task.condition().is_some())
+                            && self.adj_list[id]
+                                .iter()
+                                .any(|&(_, kind)| kind == DepKind::Normal)
+                            && !output.is_nothing()
+                            && serde_json::to_value(&output).is_ok();
+                        let restat = match outcome {
+                            Outcome::Unchanged(unchanged_since) => {
+                                task.target().and_then(Path::timestamp).map(|mtime| Restat {
+                                    mtime,
+                                    unchanged_since,
+                                    checked_at: start,
+                                })
+                            }
+                            _ => None,
+                        };
+                        self.state.update(task.name(), |state| {
+                            state.restat = restat;
+                            state.stamps = stamps;
+                            state.fingerprint = Some(task.fingerprint().to_owned());
+                            if let Some(discovered) = discovered {
+                                state.discovered = discovered;
+                            }
+                            state.output = consumed.then(|| output.clone());
+                        });
+                    }
+
+                    let output = match outcome {
+                        Outcome::Ran | Outcome::Unchanged(_) | Outcome::Restored => output,
+                        _ => self.recorded_output(id),
+                    };
+                    self.pass_output(id, output);
+                    self.settle(id, outcome, &mut run_queue);
                 }
             }
-        })?;
+        });
+
+        if !self.dry_run {
+            self.state.save()?;
+        }
+
+        let reports = self.reports.into_iter().zip(&self.tasks);
+        Ok(reports
+            .map(|(report, task)| report.unwrap_or_else(|| Report::new(task, Status::Cancelled)))
+            .collect())
+    }
+
+    /// Called once all dependencies of the task have settled.
+    fn ready(&mut self, id: usize, run_queue: &mut VecDeque<TaskId>) {
+        match self.fresh_at(id) {
+            Some(timestamp) => self.settle(id, Outcome::Fresh(timestamp), run_queue),
+            None => run_queue.push_front(TaskId(id)),
+        }
+    }
+
+    /// A task is fresh when none of its dependencies ran and it's newer than all of them.
+    fn fresh_at(&self, id: usize) -> Option<SystemTime> {
+        if self.dirty[id] {
+            return None;
+        }
+        let task = self.tasks[id];
+        let empty = BTreeMap::new();
+        let recorded = self.state.task(task.name());
+        if *recorded.map_or(&empty, |state| &state.stamps) != self.stamps[id]
+            || recorded.and_then(|state| state.fingerprint.as_deref()) != Some(task.fingerprint())
+        {
+            return None;
+        }
+        let discovered = recorded.map_or(&[][..], |state| &state.discovered);
+        let restat = recorded
+            .and_then(|state| state.restat)
+            .filter(|restat| task.target().and_then(Path::timestamp) == Some(restat.mtime));
+        let timestamp = task.cached_at(discovered, restat.map(|restat| restat.checked_at))?;
+
+        let since = restat.map_or(timestamp, |restat| restat.checked_at.max(timestamp));
+        self.latest_dep_timestamp
+            .get(&id)
+            .is_none_or(|&t| t <= since)
+            .then(|| restat.map_or(timestamp, |restat| restat.unchanged_since))
+    }
+
+    /// Propagates the outcome of the task to its dependents, settling the fresh ones in turn.
+    /// Order-only dependencies never make their dependents stale.
+    fn settle(&mut self, id: usize, outcome: Outcome, run_queue: &mut VecDeque<TaskId>) {
+        let mut settled = VecDeque::from([(id, outcome)]);
+
+        while let Some((id, outcome)) = settled.pop_front() {
+            if let Outcome::Fresh(_) = outcome {
+                let mut report = Report::new(self.tasks[id], Status::Skipped);
+                report.reason = Some("up to date");
+                self.reports[id] = Some(report);
+                self.pass_output(id, self.recorded_output(id));
+            }
+
+            for i in 0..self.adj_list[id].len() {
+                let (TaskId(next), kind) = self.adj_list[id][i];
+                self.in_degrees[next] -= 1;
+
+                if kind == DepKind::Normal {
+                    match outcome {
+                        Outcome::Ran | Outcome::Restored => self.dirty[next] = true,
+                        Outcome::Fresh(timestamp) | Outcome::Unchanged(timestamp) => {
+                            let t = self.latest_dep_timestamp.entry(next).or_insert(timestamp);
+                            *t = (*t).max(timestamp);
+                        }
+                        Outcome::Skipped => (),
+                    }
+                }
+
+                if self.in_degrees[next] == 0 {
+                    match self.fresh_at(next) {
+                        Some(timestamp) => settled.push_back((next, Outcome::Fresh(timestamp))),
+                        None => run_queue.push_front(TaskId(next)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// The value the task returned when it last ran.
+    fn recorded_output(&self, id: usize) -> Value {
+        let task = self.tasks[id];
+        self.state
+            .task(task.name())
+            .and_then(|state| state.output.clone())
+            .unwrap_or_else(|| Value::nothing(task.name.span))
+    }
+
+    /// Hands the value returned by the task to its dependents.
+    fn pass_output(&mut self, id: usize, output: Value) {
+        let name = self.tasks[id].name();
+        for &(TaskId(next), kind) in &self.adj_list[id] {
+            if kind == DepKind::Normal {
+                self.inputs[next].insert(name, output.clone());
+            }
+        }
+    }
+
+    /// Evaluates the `--vars` and `--stamp` of the task, along with the environment it runs
+    /// with, defaults from `nuke env` included.
+    fn current_stamps(
+        &self,
+        evaluator: &Evaluator,
+        id: usize,
+    ) -> Result<BTreeMap<String, String>, ShellError> {
+        let task = self.tasks[id];
+        let env = self.task_env(self.tasks[id]);
+        let config = Config::default();
+        let mut stamps: BTreeMap<_, _> = env
+            .iter()
+            .map(|(name, val)| (format!("$env.{name}"), val.to_expanded_string(" ", &config)))
+            .collect();
+
+        for name in task.vars() {
+            let val = match env.get(&name.item) {
+                Some(val) => Some(val.clone()),
+                None => evaluator.env_var(&name.item)?,
+            };
+            stamps.insert(
+                format!("$env.{}", name.item),
+                val.as_ref()
+                    .map(|val| val.to_expanded_string(" ", &config))
+                    .unwrap_or_default(),
+            );
+        }
+
+        if let Some(stamp) = task.stamp() {
+            let timeout = task.timeout().or(self.timeout);
+            let val = evaluator.eval(stamp, task.workdir(), env, &[], timeout, false)?;
+            stamps.insert("stamp".to_owned(), val.to_expanded_string(" ", &config));
+        }
+
+        Ok(stamps)
+    }
+
+    /// Picks the oldest task in `run_queue` whose pool isn't exhausted and whose weight fits in
+    /// `free_slots`. A task too heavy to fit lets lighter ones overtake it, but only `jobs` times;
+    /// after that, the freed slots are held for it.
+    fn next_runnable(
+        &self,
+        run_queue: &VecDeque<TaskId>,
+        free_slots: usize,
+        overtaken: &mut HashMap<usize, usize>,
+    ) -> Option<usize> {
+        let mut waiting = vec![];
+
+        for (pos, &TaskId(id)) in run_queue.iter().enumerate().rev() {
+            if self.pool_slots(id).is_some_and(|n| n == 0) {
+                continue;
+            }
+            if self.weight(id) <= free_slots {
+                for heavy in waiting {
+                    *overtaken.entry(heavy).or_default() += 1;
+                }
+                return Some(pos);
+            }
+            if overtaken.get(&id).is_some_and(|&n| n >= self.jobs) {
+                return None;
+            }
+            waiting.push(id);
+        }
 
-        Ok(())
+        None
+    }
+
+    /// The number of job slots the task holds while running.
+    fn weight(&self, id: usize) -> usize {
+        let task = self.tasks[id];
+        if task.pool().is_some_and(|pool| pool.item == CONSOLE_POOL) {
+            return self.jobs;
+        }
+
+        match task.weight() {
+            Weight::Slots(n) => n.get().min(self.jobs),
+            Weight::All => self.jobs,
+        }
+    }
+
+    fn pool_slots(&self, id: usize) -> Option<usize> {
+        let pool = self.tasks[id].pool()?;
+        Some(self.pools[&pool.item])
+    }
+
+    /// The task's environment variables take precedence over the defaults.
+    pub fn task_env(&self, task: &Task) -> Record {
+        let mut env = self.env.clone();
+        for (name, val) in task.env() {
+            env.insert(name, val.clone());
+        }
+        env
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TaskId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepKind {
+    Normal,
+    /// Only orders the execution, see `--after`.
+    OrderOnly,
+}
+
+/// How a task went in a run.
+#[derive(Debug)]
+pub struct Report {
+    pub name: String,
+    pub status: Status,
+    pub start: Option<SystemTime>,
+    pub duration: Option<Duration>,
+    /// How many times the closure was evaluated, for tasks that ran it.
+    pub attempts: Option<usize>,
+    /// Why the task was skipped.
+    pub reason: Option<&'static str>,
+    pub error: Option<ShellError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ran,
+    Skipped,
+    Failed,
+    /// Not started because another task failed.
+    Cancelled,
+}
+
+impl Report {
+    fn new(task: &Task, status: Status) -> Self {
+        Self {
+            name: task.name().to_owned(),
+            status,
+            start: None,
+            duration: None,
+            attempts: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    pub fn into_value(self, span: Span) -> Value {
+        let status = match self.status {
+            Status::Ran => "ran",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        };
+        let start = self.start.map_or(Value::nothing(span), |start| {
+            Value::date(DateTime::<Local>::from(start).fixed_offset(), span)
+        });
+        let duration = self.duration.map_or(Value::nothing(span), |duration| {
+            Value::duration(duration.as_nanos().try_into().unwrap_or(i64::MAX), span)
+        });
+        let attempts = self.attempts.map_or(Value::nothing(span), |attempts| {
+            Value::int(attempts as i64, span)
+        });
+        let reason = self
+            .reason
+            .map_or(Value::nothing(span), |reason| Value::string(reason, span));
+        // Kept as an error, so nu renders it with its spans once it's used.
+        let error = self
+            .error
+            .map_or(Value::nothing(span), |error| Value::error(error, span));
+
+        Value::record(
+            record! {
+                "name" => Value::string(self.name, span),
+                "status" => Value::string(status, span),
+                "start" => start,
+                "duration" => duration,
+                "attempts" => attempts,
+                "reason" => reason,
+                "error" => error,
+            },
+            span,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    Ran,
+    /// Up to date since the timestamp.
+    Fresh(SystemTime),
+    /// Its condition didn't hold.
+    Skipped,
+    /// Its target was restored from the cache instead.
+    Restored,
+    /// Ran, but left its target as it was since the timestamp, see `--restat`.
+    Unchanged(SystemTime),
+}
+
+/// Evaluates the predicate given by `--if`, which holds for tasks without one.
+fn condition_holds(
+    evaluator: &Evaluator,
+    task: &Task,
+    env: &Record,
+    timeout: Option<Duration>,
+) -> Result<bool, ShellError> {
+    let Some(condition) = task.condition() else {
+        return Ok(true);
+    };
+
+    evaluator
+        .eval(condition, task.workdir(), env.clone(), &[], timeout, false)?
+        .as_bool()
+}
+
+fn print_plan(task: &Task, env: &Record, timeout: Option<Duration>) {
+    println!("Would run task `{}`", task.name());
+    println!("    cwd = {}", task.workdir().display());
+    if let Some(pool) = task.pool() {
+        println!("    pool = {}", pool.item);
+    }
+    match task.weight() {
+        Weight::Slots(n) if n.get() == 1 => (),
+        Weight::Slots(n) => println!("    weight = {n}"),
+        Weight::All => println!("    weight = all"),
+    }
+    if let Some(timeout) = timeout {
+        println!("    timeout = {}", humanize(timeout));
+    }
+    if task.retry() > 0 {
+        println!(
+            "    retry = {} (delay {})",
+            task.retry(),
+            humanize(task.retry_delay())
+        );
+    }
+    let config = Config::default();
+    for (name, val) in env {
+        println!("    env {name} = {}", val.to_expanded_string(" ", &config));
+    }
+}
+
+/// Runs the task, unless its target can be restored from the cache,
+/// returning the number of attempts at running it along with the result.
+fn build(
+    evaluator: &Evaluator,
+    task: &Task,
+    env: Record,
+    input: Record,
+    timeout: Option<Duration>,
+    cached: Option<(&Cache, String)>,
+) -> (usize, Result<(Outcome, Value), ShellError>) {
+    let cached = match (cached, task.target()) {
+        (Some((cache, key)), Some(target)) if task.run().is_some() => Some((cache, target, key)),
+        _ => None,
+    };
+    if let Err(e) = create_target_dir(task) {
+        return (0, Err(e));
+    }
+
+    if let Some((cache, target, key)) = &cached {
+        match cache.restore(key, target, task.name.span) {
+            Ok(Some(output)) => {
+                println!("Restored task `{}` from cache", task.name());
+                return (0, Ok((Outcome::Restored, output)));
+            }
+            Ok(None) => (),
+            Err(e) => eprintln!("Failed to restore task `{}` from cache: {e}", task.name()),
+        }
+    }
+
+    println!("Running task `{}`", task.name());
+    let before = task.target().and_then(Path::timestamp);
+    let digest = task
+        .target()
+        .filter(|_| task.restat())
+        .and_then(Path::digest);
+    let (attempts, res) = run_task(evaluator, task, env, input, timeout);
+    let res = res.and_then(|output| {
+        check_target(task, before)?;
+        Ok(output)
+    });
+    if res.is_err() {
+        discard_target(task, before);
+    }
+    let output = match res {
+        Ok(output) => output,
+        Err(e) => return (attempts, Err(e)),
+    };
+
+    if let Some((cache, target, key)) = cached
+        && target.is_file()
+        && let Err(e) = cache.store(&key, target, &output)
+    {
+        eprintln!("Failed to cache the target of task `{}`: {e}", task.name());
+    }
+    match unchanged_since(task, before, digest) {
+        Some(timestamp) => (attempts, Ok((Outcome::Unchanged(timestamp), output))),
+        None => (attempts, Ok((Outcome::Ran, output))),
+    }
+}
+
+/// The former timestamp of the target if the task restats it and the run left it as it was,
+/// either untouched or rewritten with the same contents.
+fn unchanged_since(
+    task: &Task,
+    before: Option<SystemTime>,
+    digest: Option<blake3::Hash>,
+) -> Option<SystemTime> {
+    let target = task.target().filter(|_| task.restat())?;
+    let before = before?;
+
+    if target.timestamp()? == before {
+        return Some(before);
+    }
+    (target.digest()? == digest?).then_some(before)
+}
+
+/// The inputs listed in the depfile of the task, relative to its working directory.
+fn read_depfile(task: &Task) -> Option<Vec<PathBuf>> {
+    let depfile = task.depfile()?;
+
+    match fs::read_to_string(depfile) {
+        Ok(content) => Some(
+            depfile::parse(&content)
+                .into_iter()
+                .map(|dep| task.workdir().join(dep))
+                .collect(),
+        ),
+        Err(e) => {
+            eprintln!(
+                "Failed to read the depfile `{}` of task `{}`: {e}",
+                depfile.display(),
+                task.name()
+            );
+            None
+        }
+    }
+}
+
+fn create_target_dir(task: &Task) -> Result<(), ShellError> {
+    let Some(dir) = task.target().and_then(Path::parent) else {
+        return Ok(());
+    };
+
+    fs::create_dir_all(dir).map_err(|e| {
+        ShellError::Io(IoError::new_with_additional_context(
+            e.kind(),
+            task.name.span,
+            dir.to_path_buf(),
+            "Failed to create the directory of the target",
+        ))
+    })
+}
+
+/// Removes the target file if the failed run changed it, lest it pass for up to date next time.
+fn discard_target(task: &Task, before: Option<SystemTime>) {
+    let Some(target) = task.target() else {
+        return;
+    };
+    if task.keep_failed_target() || target.is_dir() {
+        return;
+    }
+
+    if target
+        .timestamp()
+        .is_some_and(|after| Some(after) != before)
+    {
+        match fs::remove_file(target) {
+            Ok(()) => eprintln!(
+                "Removed `{}` left by failed task `{}`",
+                target.display(),
+                task.name()
+            ),
+            Err(e) => eprintln!("Failed to remove `{}`: {e}", target.display()),
+        }
+    }
+}
+
+/// Fails unless the run created or updated the target, given its timestamp beforehand.
+fn check_target(task: &Task, before: Option<SystemTime>) -> Result<(), ShellError> {
+    let Some(target) = task.target() else {
+        return Ok(());
+    };
+    if !task.check_target() || task.run().is_none() {
+        return Ok(());
+    }
+
+    // The timestamp of a directory only changes with its direct entries,
+    // so rebuilding the files in it may leave it as it was.
+    let msg = match target.timestamp() {
+        None => "the target wasn't created",
+        Some(after) if Some(after) == before && !task.restat() && !target.is_dir() => {
+            "the target wasn't updated"
+        }
+        Some(_) => return Ok(()),
+    };
+    Err(ShellError::GenericError {
+        error: format!("Task `{}` didn't build `{}`", task.name(), target.display()),
+        msg: msg.into(),
+        span: Some(task.name.span),
+        help: Some("Pass `--no-target-check` to the task if that's expected".into()),
+        inner: vec![],
+    })
+}
+
+/// Evaluates the task's closure, retrying on failure as many times as the task allows.
+/// A timed out closure may still be running, so it isn't retried.
+/// The values returned by its dependencies are passed after the other arguments.
+/// Returns the number of attempts along with the result of the last one.
+fn run_task(
+    evaluator: &Evaluator,
+    task: &Task,
+    env: Record,
+    input: Record,
+    timeout: Option<Duration>,
+) -> (usize, Result<Value, ShellError>) {
+    let Some(run) = task.run() else {
+        return (0, Ok(Value::nothing(task.name.span)));
+    };
+    let mut args = task.args().to_vec();
+    args.push(Value::record(input, run.span));
+
+    let attempts = task.retry() + 1;
+    for attempt in 1..=attempts {
+        let console = task.pool().is_some_and(|pool| pool.item == CONSOLE_POOL);
+        match evaluator.eval(run, task.workdir(), env.clone(), &args, timeout, console) {
+            Ok(output) => {
+                if attempt > 1 {
+                    println!("Task `{}` succeeded after {attempt} attempts", task.name());
+                }
+                return (attempt, Ok(output));
+            }
+            Err(e) if attempt < attempts && !eval::timed_out(&e) => {
+                eprintln!(
+                    "Task `{}` failed (attempt {attempt}/{attempts}): {e}",
+                    task.name()
+                );
+                eprintln!("Retrying in {}", humanize(task.retry_delay()));
+                thread::sleep(task.retry_delay());
+            }
+            Err(e) => {
+                if attempt > 1 {
+                    eprintln!("Task `{}` failed after {attempt} attempts", task.name());
+                }
+                return (attempt, Err(e));
+            }
+        }
+    }
+
+    unreachable!()
+}
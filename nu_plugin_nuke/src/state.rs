@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use nu_protocol::shell_error::io::IoError;
+use nu_protocol::{ShellError, Value};
+use serde::{Deserialize, Serialize};
+
+/// What nuke remembers of the tasks between runs, stored in `.nuke/state.json`.
+#[derive(Debug, Default, Serialize)]
+pub struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    modified: bool,
+    tasks: BTreeMap<String, TaskState>,
+}
+
+/// The state file as read, with the tasks left to parse one by one.
+#[derive(Default, Deserialize)]
+struct Stored {
+    #[serde(default)]
+    tasks: BTreeMap<String, serde_json::Value>,
+}
+
+/// Recorded at the last successful run of a task.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskState {
+    /// The values of `--vars` and `--stamp`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stamps: BTreeMap<String, String>,
+    /// See [`Task::fingerprint`](crate::Task::fingerprint).
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// The inputs listed by the `--depfile` of the task.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discovered: Vec<PathBuf>,
+    /// The value returned by the task, handed to its dependents as long as it's skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<Value>,
+    /// Left by a run of a `--restat` task that didn't change its target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restat: Option<Restat>,
+}
+
+/// Stands for the timestamp of a target that a `--restat` run left as it was,
+/// as long as the target keeps the timestamp the run left it with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Restat {
+    /// The timestamp of the target after the run.
+    pub mtime: SystemTime,
+    /// Since when the target has had its contents, which dependents are compared against.
+    pub unchanged_since: SystemTime,
+    /// When the run started, which the inputs are compared against.
+    pub checked_at: SystemTime,
+}
+
+impl State {
+    /// A missing or unreadable state file leaves the state empty,
+    /// while a task whose record can't be read only loses that record.
+    pub fn load(root: &Path) -> Self {
+        let path = root.join(".nuke").join("state.json");
+        let stored: Stored = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        let tasks = stored
+            .tasks
+            .into_iter()
+            .filter_map(|(name, task)| Some((name, serde_json::from_value(task).ok()?)))
+            .collect();
+
+        Self {
+            path,
+            modified: false,
+            tasks,
+        }
+    }
+
+    pub fn task(&self, name: &str) -> Option<&TaskState> {
+        self.tasks.get(name)
+    }
+
+    pub fn update(&mut self, name: &str, f: impl FnOnce(&mut TaskState)) {
+        let task = self.tasks.entry(name.to_owned()).or_default();
+        let old = task.clone();
+        f(task);
+        self.modified |= *task != old;
+    }
+
+    pub fn save(&self) -> Result<(), ShellError> {
+        if !self.modified {
+            return Ok(());
+        }
+
+        self.save_impl().map_err(|e| {
+            ShellError::Io(IoError::new_internal_with_path(
+                e.kind(),
+                "Failed to save the state of nuke",
+                nu_protocol::location!(),
+                self.path.clone(),
+            ))
+        })
+    }
+
+    fn save_impl(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Written aside first, so an interruption can't leave a truncated file.
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::State;
+
+    #[test]
+    fn unreadable_tasks_are_dropped_alone() {
+        let root = std::env::temp_dir().join(format!("nuke-state-{}", std::process::id()));
+        fs::create_dir_all(root.join(".nuke")).unwrap();
+        let content = r#"{
+            "tasks": {
+                "build": { "fingerprint": "abc" },
+                "version": { "fingerprint": "def", "output": { "NotAValue": 1 } }
+            }
+        }"#;
+        fs::write(root.join(".nuke").join("state.json"), content).unwrap();
+
+        let state = State::load(&root);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            state.task("build").unwrap().fingerprint.as_deref(),
+            Some("abc")
+        );
+        assert!(state.task("version").is_none());
+    }
+}
@@ -1,7 +1,8 @@
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use nu_protocol::{Spanned, engine::Closure};
+use nu_protocol::{Record, Spanned, Value, engine::Closure};
 
 use crate::utils::path::PathExt;
 
@@ -9,9 +10,45 @@ use crate::utils::path::PathExt;
 pub struct Task {
     pub(crate) name: Spanned<String>,
     pub(crate) deps: Vec<Spanned<String>>,
+    pub(crate) deps_fn: Option<Spanned<Closure>>,
+    pub(crate) namespace: Option<String>,
+    pub(crate) after: Vec<Spanned<String>>,
+    pub(crate) optional_deps: Vec<Spanned<String>>,
     pub(crate) files: Vec<PathBuf>,
     pub(crate) target: Option<PathBuf>,
+    pub(crate) clean: Vec<PathBuf>,
+    pub(crate) depfile: Option<PathBuf>,
+    pub(crate) check_target: bool,
+    pub(crate) keep_failed_target: bool,
+    pub(crate) restat: bool,
+    pub(crate) workdir: PathBuf,
     pub(crate) run: Option<Spanned<Closure>>,
+    pub(crate) args: Vec<Value>,
+    pub(crate) condition: Option<Spanned<Closure>>,
+    pub(crate) vars: Vec<Spanned<String>>,
+    pub(crate) stamp: Option<Spanned<Closure>>,
+    pub(crate) env: Record,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retry: usize,
+    pub(crate) retry_delay: Duration,
+    pub(crate) pool: Option<Spanned<String>>,
+    pub(crate) weight: Weight,
+    pub(crate) recipe: String,
+    pub(crate) fingerprint: String,
+    pub(crate) private: bool,
+}
+
+/// The number of job slots a task holds while running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    Slots(NonZeroUsize),
+    All,
+}
+
+impl Default for Weight {
+    fn default() -> Self {
+        Self::Slots(NonZeroUsize::MIN)
+    }
 }
 
 impl Task {
@@ -23,12 +60,20 @@ impl Task {
         self.name.as_deref()
     }
 
-    pub fn cached_at(&self) -> Option<SystemTime> {
+    /// `discovered` are the inputs found in the depfile by the last run.
+    /// `checked_at` is when a `--restat` run last found the target up to date,
+    /// which the inputs are compared against if it's later than the target.
+    pub fn cached_at(
+        &self,
+        discovered: &[PathBuf],
+        checked_at: Option<SystemTime>,
+    ) -> Option<SystemTime> {
         let out_mtime = self.target.as_ref()?.timestamp()?;
+        let since = checked_at.map_or(out_mtime, |checked_at| checked_at.max(out_mtime));
 
-        for dep in &self.files {
+        for dep in self.files.iter().chain(discovered) {
             let dep_mtime = Path::new(dep).timestamp()?;
-            if dep_mtime > out_mtime {
+            if dep_mtime > since {
                 return None;
             }
         }
@@ -39,7 +84,167 @@ impl Task {
         self.run.as_ref()
     }
 
+    /// The positional arguments of `run`.
+    pub fn args(&self) -> &[Value] {
+        &self.args
+    }
+
+    /// The predicate deciding whether the task should run at all.
+    pub fn condition(&self) -> Option<&Spanned<Closure>> {
+        self.condition.as_ref()
+    }
+
+    /// The environment variables whose changes make the task stale.
+    pub fn vars(&self) -> &[Spanned<String>] {
+        &self.vars
+    }
+
+    /// The closure whose output changes make the task stale.
+    pub fn stamp(&self) -> Option<&Spanned<Closure>> {
+        self.stamp.as_ref()
+    }
+
     pub fn deps(&self) -> &[Spanned<String>] {
         &self.deps
     }
+
+    /// The closure computing more dependencies when the task is submitted.
+    pub fn deps_fn(&self) -> Option<&Spanned<Closure>> {
+        self.deps_fn.as_ref()
+    }
+
+    /// The namespace given by `nuke include` to the makefile defining the task.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    pub fn target(&self) -> Option<&Path> {
+        self.target.as_deref()
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// The Makefile-format file where the run lists the inputs it read.
+    pub fn depfile(&self) -> Option<&Path> {
+        self.depfile.as_deref()
+    }
+
+    /// Whether a successful run must create or update the target.
+    pub fn check_target(&self) -> bool {
+        self.check_target
+    }
+
+    /// Whether a run leaving the target unchanged leaves the dependents fresh.
+    pub fn restat(&self) -> bool {
+        self.restat
+    }
+
+    /// Whether a target changed by a failed run is kept rather than removed.
+    pub fn keep_failed_target(&self) -> bool {
+        self.keep_failed_target
+    }
+
+    /// The side outputs removed along with the target by `nuke clean`.
+    pub fn clean(&self) -> &[PathBuf] {
+        &self.clean
+    }
+
+    pub fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    /// The tasks to run first if they're defined.
+    pub fn optional_deps(&self) -> &[Spanned<String>] {
+        &self.optional_deps
+    }
+
+    /// The tasks to run first, whose running doesn't make this task stale.
+    pub fn after(&self) -> &[Spanned<String>] {
+        &self.after
+    }
+
+    pub fn env(&self) -> &Record {
+        &self.env
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The number of extra attempts after a failure.
+    pub fn retry(&self) -> usize {
+        self.retry
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
+    pub fn pool(&self) -> Option<&Spanned<String>> {
+        self.pool.as_ref()
+    }
+
+    pub fn weight(&self) -> Weight {
+        self.weight
+    }
+
+    /// Changes with the source of the task's closures only.
+    pub fn recipe(&self) -> &str {
+        &self.recipe
+    }
+
+    /// Changes with the source of the task's closures and its flags.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Private tasks are only run as dependencies, and hidden from listings.
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+}
+
+/// Puts the task name into the namespace, unless it starts with `:`, referring to the root.
+pub fn qualify(namespace: Option<&str>, name: Spanned<String>) -> Spanned<String> {
+    let item = match (name.item.strip_prefix(':'), namespace) {
+        (Some(item), _) => item.to_owned(),
+        (None, Some(namespace)) => format!("{namespace}:{}", name.item),
+        (None, None) => return name,
+    };
+
+    Spanned {
+        item,
+        span: name.span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nu_protocol::{Span, Spanned};
+
+    use super::qualify;
+
+    fn qualified(namespace: Option<&str>, name: &str) -> String {
+        let name = Spanned {
+            item: name.to_owned(),
+            span: Span::test_data(),
+        };
+        qualify(namespace, name).item
+    }
+
+    #[test]
+    fn namespaced_names() {
+        assert_eq!(qualified(Some("web"), "build"), "web:build");
+        assert_eq!(qualified(Some("web"), "dist/app.js"), "web:dist/app.js");
+        assert_eq!(qualified(None, "build"), "build");
+    }
+
+    #[test]
+    fn rooted_names() {
+        assert_eq!(qualified(Some("web"), ":build"), "build");
+        assert_eq!(qualified(Some("web"), ":api:build"), "api:build");
+        assert_eq!(qualified(None, ":build"), "build");
+    }
 }
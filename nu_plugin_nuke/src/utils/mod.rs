@@ -1,5 +1,31 @@
 pub mod collections;
 
+pub mod call {
+    use std::time::Duration;
+
+    use easy_ext::ext;
+    use nu_plugin::EvaluatedCall;
+    use nu_protocol::ShellError;
+
+    #[ext(EvaluatedCallExt)]
+    pub impl EvaluatedCall {
+        fn get_duration_flag(&self, name: &str) -> Result<Option<Duration>, ShellError> {
+            let Some(val) = self.get_flag_value(name) else {
+                return Ok(None);
+            };
+
+            let nanos = val.as_duration()?;
+            u64::try_from(nanos)
+                .map(|nanos| Some(Duration::from_nanos(nanos)))
+                .map_err(|_| ShellError::IncorrectValue {
+                    msg: format!("<{name}> should be positive"),
+                    val_span: val.span(),
+                    call_span: self.head,
+                })
+        }
+    }
+}
+
 pub mod path {
     use std::path::Path;
     use std::time::SystemTime;
@@ -13,5 +39,24 @@ pub mod path {
                 .and_then(|metadata| metadata.modified())
                 .ok()
         }
+
+        /// The hash of the file's contents.
+        fn digest(&self) -> Option<blake3::Hash> {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_reader(std::fs::File::open(self).ok()?).ok()?;
+            Some(hasher.finalize())
+        }
+    }
+}
+
+pub mod time {
+    use std::time::Duration;
+
+    use nu_protocol::{Config, Span, Value};
+
+    /// Formats like nushell's durations, e.g. `1min 30sec`.
+    pub fn humanize(duration: Duration) -> String {
+        Value::duration(duration.as_nanos() as i64, Span::unknown())
+            .to_expanded_string("", &Config::default())
     }
 }
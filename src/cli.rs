@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 #[derive(Debug, Parser)]
 pub struct Cli {
@@ -6,14 +6,75 @@ pub struct Cli {
     #[arg(long, short, value_name = "N")]
     pub jobs: Option<usize>,
 
+    /// The longest time to wait for the output of a task, e.g. `10min`; the task isn't interrupted
+    #[arg(long, value_name = "DURATION")]
+    pub timeout: Option<String>,
+
+    /// Optional dependencies to run even with `--no-optional`
+    #[arg(long, value_name = "TASK", value_delimiter = ',')]
+    pub with: Vec<String>,
+
+    /// Optional dependencies not to run
+    #[arg(long, value_name = "TASK", value_delimiter = ',')]
+    pub without: Vec<String>,
+
+    /// Run no optional dependencies but those passed to `--with`
+    #[arg(long)]
+    pub no_optional: bool,
+
+    /// Print the tasks to run without running them
+    #[arg(long, short = 'n')]
+    pub dry_run: bool,
+
+    /// List the public tasks instead of running any
+    #[arg(long, short, conflicts_with_all = ["name", "clean"])]
+    pub list: bool,
+
+    /// Remove the targets of the task and its dependencies, or of all tasks
+    #[arg(long)]
+    pub clean: bool,
+
+    /// Manage the cache of targets set up by `NUKE_CACHE_DIR`
+    #[arg(long, value_name = "ACTION", conflicts_with_all = ["name", "list", "clean"])]
+    pub cache: Option<CacheAction>,
+
     /// Task to run
     pub name: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CacheAction {
+    /// Show the size of the cache
+    Stats,
+    /// Evict the least recently used targets beyond `NUKE_CACHE_MAX_SIZE`
+    Prune,
+}
+
+impl std::fmt::Display for CacheAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stats => f.write_str("nuke cache stats"),
+            Self::Prune => f.write_str("nuke cache prune"),
+        }
+    }
+}
+
 impl Cli {
+    pub fn nuke_clean(&self) -> NukeClean<'_> {
+        NukeClean {
+            dry_run: self.dry_run,
+            name: self.name.as_deref(),
+        }
+    }
+
     pub fn nuke_schedule(&self) -> NukeSchedule<'_> {
         NukeSchedule {
             jobs: self.jobs,
+            timeout: self.timeout.as_deref(),
+            with: &self.with,
+            without: &self.without,
+            no_optional: self.no_optional,
+            dry_run: self.dry_run,
             name: self.name.as_deref(),
         }
     }
@@ -22,6 +83,11 @@ impl Cli {
 #[derive(Debug)]
 pub struct NukeSchedule<'s> {
     jobs: Option<usize>,
+    timeout: Option<&'s str>,
+    with: &'s [String],
+    without: &'s [String],
+    no_optional: bool,
+    dry_run: bool,
     name: Option<&'s str>,
 }
 
@@ -31,9 +97,59 @@ impl std::fmt::Display for NukeSchedule<'_> {
         if let Some(n) = self.jobs {
             write!(f, " --jobs={n}")?;
         }
+        if let Some(timeout) = self.timeout {
+            write!(f, " --timeout={timeout}")?;
+        }
+        if !self.with.is_empty() {
+            write!(f, " --with={}", NuList(self.with))?;
+        }
+        if !self.without.is_empty() {
+            write!(f, " --without={}", NuList(self.without))?;
+        }
+        if self.no_optional {
+            f.write_str(" --no-optional")?;
+        }
+        if self.dry_run {
+            f.write_str(" --dry-run")?;
+        }
         if let Some(name) = self.name {
             write!(f, " {name}")?;
         }
         Ok(())
     }
 }
+
+#[derive(Debug)]
+pub struct NukeClean<'s> {
+    dry_run: bool,
+    name: Option<&'s str>,
+}
+
+impl std::fmt::Display for NukeClean<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("nuke clean")?;
+        if self.dry_run {
+            f.write_str(" --dry-run")?;
+        }
+        if let Some(name) = self.name {
+            write!(f, " {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders as a list literal of nushell.
+struct NuList<'s>(&'s [String]);
+
+impl std::fmt::Display for NuList<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{item:?}")?;
+        }
+        f.write_str("]")
+    }
+}
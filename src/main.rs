@@ -21,14 +21,36 @@ fn main() -> Result<(), Error> {
     }
 
     let plugin = which("nu_plugin_nuke").map_err(Error::plugin)?;
+    let command = if let Some(action) = cli.cache {
+        action.to_string()
+    } else if cli.list {
+        "nuke list".to_owned()
+    } else if cli.clean {
+        cli.nuke_clean().to_string()
+    } else {
+        formatdoc! {"
+            let tasks = {}
+            let cancelled = $tasks | where status == cancelled | length
+            if $cancelled > 0 {{
+                print --stderr $'nuke: ($cancelled) tasks cancelled'
+            }}
+            let failed = $tasks | where status == failed
+            for task in $failed {{
+                print --stderr $'nuke: task `($task.name)` failed'
+            }}
+            if ($failed | is-not-empty) {{
+                $failed.0.error
+            }}",
+            cli.nuke_schedule()
+        }
+    };
 
     let status = Command::new("nu")
         .args([
             "-c",
             &formatdoc! {"
                 source make.nu
-                {}",
-                cli.nuke_schedule()
+                {command}"
             },
             &format!("--plugins=[{}]", plugin.display()),
         ])